@@ -3,13 +3,21 @@ use bitflags::bitflags;
 use fakeenv::EnvStore;
 use std::{
     borrow::Cow,
-    error,
-    ffi::OsStr,
-    fmt, fs, io,
-    marker::PhantomData,
+    cmp,
+    collections::HashSet,
+    env, error,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, IsTerminal, Read, Seek, Write},
+    iter,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
-    rc::Rc,
+    process::{self, Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -21,9 +29,54 @@ impl Error {
         Self(ErrorKind::Io(err))
     }
 
+    pub(crate) fn io_context(err: io::Error, context: impl Into<Cow<'static, str>>) -> Self {
+        Self(ErrorKind::Context {
+            context: context.into(),
+            source: err,
+        })
+    }
+
     pub(crate) fn msg(msg: impl Into<Cow<'static, str>>) -> Self {
         Self(ErrorKind::Msg(msg.into()))
     }
+
+    pub(crate) fn exit(program: impl Into<String>, code: Option<i32>) -> Self {
+        Self(ErrorKind::Exit {
+            program: program.into(),
+            code,
+        })
+    }
+
+    /// Creates an error with the given message, for use by code built on top of this
+    /// crate.
+    pub fn message(msg: impl Into<Cow<'static, str>>) -> Self {
+        Self::msg(msg)
+    }
+
+    /// Creates the error reported when `program` exits with the given status, as returned
+    /// by `Subprocess::status`.
+    ///
+    /// The status is expected to be a failure; this does not check it.
+    pub fn from_exit(status: ExitStatus, program: &str) -> Self {
+        Self::exit(program, status.code())
+    }
+
+    /// Returns the exit code of the subprocess if this error was caused by its failure.
+    ///
+    /// `None` is returned for other kinds of errors, and also when the subprocess was
+    /// terminated by a signal.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.0 {
+            ErrorKind::Exit { code, .. } => code,
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::io_error(err)
+    }
 }
 
 impl fmt::Debug for Error {
@@ -34,15 +87,33 @@ impl fmt::Debug for Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(&self.0, formatter)
+        match self.0 {
+            ErrorKind::Io(ref err) => fmt::Display::fmt(err, formatter),
+            ErrorKind::Context {
+                ref context,
+                ref source,
+            } => write!(formatter, "{}: {}", context, source),
+            ErrorKind::Msg(ref msg) => formatter.write_str(msg),
+            ErrorKind::Exit {
+                ref program,
+                code: Some(code),
+            } => write!(formatter, "{} exited with code {}", program, code),
+            ErrorKind::Exit {
+                ref program,
+                code: None,
+            } => write!(formatter, "{} was terminated by a signal", program),
+        }
     }
 }
 
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self.0 {
-            ErrorKind::Io(ref err) => Some(err),
-            ErrorKind::Msg(..) => None,
+            ErrorKind::Io(ref err)
+            | ErrorKind::Context {
+                source: ref err, ..
+            } => Some(err),
+            ErrorKind::Msg(..) | ErrorKind::Exit { .. } => None,
         }
     }
 }
@@ -50,57 +121,414 @@ impl error::Error for Error {
 #[derive(Debug)]
 enum ErrorKind {
     Io(io::Error),
+    Context {
+        context: Cow<'static, str>,
+        source: io::Error,
+    },
     Msg(Cow<'static, str>),
+    Exit {
+        program: String,
+        code: Option<i32>,
+    },
 }
 
 /// The minimal implementation of shell for xtask scripts.
+///
+/// `Shell` is `Send + Sync`, so it can be shared across the threads of a parallel
+/// runner. Subprocesses spawned concurrently each take their own snapshot of the
+/// environment at the time `subprocess` is called.
+///
+/// A clone shares the environment and the dry-run log with the original; use `with_env`
+/// for a copy with its own environment.
+#[derive(Clone)]
 pub struct Shell {
     env_store: EnvStore,
     project_root: PathBuf,
+    cwd: PathBuf,
     target_dir: PathBuf,
-    _anchor: PhantomData<Rc<()>>, // FIXME: make thread safe
+    dry_run: bool,
+    verbose: bool,
+    color: ColorChoice,
+    quoting: Quoting,
+    dry_run_log: DryRunLog,
+    observer: Option<Arc<dyn ShellObserver>>,
+}
+
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Shell>();
+};
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Shell {
+    /// Creates a shell for the xtask being run by cargo.
+    ///
+    /// The project root is found from `CARGO_MANIFEST_DIR`, or if that is not set at run
+    /// time, e.g. when the xtask binary is run directly, from its value when this crate
+    /// was built (see `ShellBuilder::build`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the shell cannot be built. Under cargo, the build-time fallback means
+    /// this does not happen for a missing `CARGO_MANIFEST_DIR`; use `Shell::builder` to
+    /// handle errors gracefully.
     pub fn new() -> Self {
-        let envs = EnvStore::fake();
+        Self::builder()
+            .build()
+            .unwrap_or_else(|err| panic!("failed to initialize the shell: {}", err))
+    }
 
-        let manifest_dir = envs
-            .var_os("CARGO_MANIFEST_DIR")
-            .map(PathBuf::from)
-            .or_else(|| option_env!("CARGO_MANIFEST_DIR").map(PathBuf::from))
-            .expect("missing CARGO_MANIFEST_DIR");
-        let project_root = manifest_dir.ancestors().nth(1).unwrap().to_path_buf();
+    pub fn builder() -> ShellBuilder {
+        ShellBuilder::default()
+    }
 
-        let target_dir = envs
-            .var_os("CARGO_TARGET_DIR")
-            .map(PathBuf::from)
-            .unwrap_or_else(|| project_root.join("target"));
+    /// Creates a shell whose environment, including `CARGO_MANIFEST_DIR`, is fully
+    /// provided by the given store. This fails if the store does not set
+    /// `CARGO_MANIFEST_DIR`.
+    pub fn with_env_store(env_store: EnvStore) -> Result<Self> {
+        Self::builder().env_store(env_store).build()
+    }
 
-        Self {
-            env_store: envs,
-            project_root,
-            target_dir,
-            _anchor: PhantomData,
+    /// Returns the environment used for lookups and spawned subprocesses.
+    pub fn env_store(&self) -> &EnvStore {
+        &self.env_store
+    }
+
+    /// Returns whether subprocesses are skipped instead of being run.
+    ///
+    /// This is initialized from the `DRY_RUN` environment variable.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Toggles the dry-run mode for subprocesses created afterwards.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Returns the command lines skipped so far in dry-run mode, in order, by this shell
+    /// and the subprocesses created from it.
+    ///
+    /// This is a copy, since subprocesses can keep adding to the log from other threads.
+    pub fn dry_run_log(&self) -> Vec<String> {
+        self.dry_run_log.lock().clone()
+    }
+
+    /// Controls whether a command skipped in dry-run mode is reported on stderr, which is
+    /// the default. It is recorded in `dry_run_log` either way.
+    pub fn set_dry_run_echo(&mut self, echo: bool) {
+        self.dry_run_log.echo = echo;
+    }
+
+    /// Returns whether this seems to be running on a continuous integration service.
+    ///
+    /// This looks at the `CI` variable, set by most providers, as well as the variables
+    /// specific to a few common ones that do not set it.
+    pub fn is_ci(&self) -> bool {
+        const PROVIDER_VARS: &[&str] = &[
+            "GITHUB_ACTIONS",
+            "GITLAB_CI",
+            "TRAVIS",
+            "CIRCLECI",
+            "BUILDKITE",
+            "APPVEYOR",
+            "TF_BUILD",
+            "JENKINS_URL",
+            "TEAMCITY_VERSION",
+        ];
+
+        match self.env_store.var_os("CI") {
+            Some(ci) => ci != "false" && ci != "0",
+            None => PROVIDER_VARS
+                .iter()
+                .any(|var| self.env_store.var_os(var).is_some()),
+        }
+    }
+
+    /// Enables echoing each command to stderr before it runs, like `set -x`.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Routes the commands run by subprocesses created afterwards to `observer` instead
+    /// of echoing them to stderr in verbose mode.
+    ///
+    /// The observer is told about each command right before it starts, regardless of
+    /// `set_verbose`, and once it has finished, including each stage of a `Pipeline` and
+    /// each step of a `Sequence`. A spawned command finishes when its `Child` is waited
+    /// for or dropped. Commands skipped in dry-run mode are not reported.
+    pub fn set_observer(&mut self, observer: impl ShellObserver + 'static) {
+        self.observer = Some(Arc::new(observer));
+    }
+
+    /// Controls whether the echoed commands are colorized.
+    ///
+    /// This is initialized from `CARGO_TERM_COLOR`, and defaults to `ColorChoice::Auto`
+    /// which only enables colors when stderr is a terminal.
+    pub fn set_color(&mut self, color: ColorChoice) {
+        self.color = color;
+    }
+
+    /// Returns the quoting rules used to echo commands and to split the arguments given
+    /// to `Subprocess::args_from_str`.
+    pub fn quoting(&self) -> Quoting {
+        self.quoting
+    }
+
+    /// Sets the quoting rules, which default to those of the host platform, for
+    /// subprocesses created afterwards.
+    pub fn set_quoting(&mut self, quoting: Quoting) {
+        self.quoting = quoting;
+    }
+
+    fn use_color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => io::stderr().is_terminal(),
+        }
+    }
+
+    /// Sets an environment variable for all subprocesses spawned afterwards.
+    ///
+    /// Subprocesses that were already created keep the environment they were created
+    /// with.
+    pub fn set_var(&mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) {
+        self.env_store.set_var(key, val);
+    }
+
+    /// Removes an environment variable for all subprocesses spawned afterwards.
+    ///
+    /// Subprocesses that were already created keep the environment they were created
+    /// with.
+    pub fn remove_var(&mut self, key: impl AsRef<OsStr>) {
+        self.env_store.remove_var(key);
+    }
+
+    /// Puts a directory in front of `PATH` for all subprocesses spawned afterwards, and
+    /// for `which`. `PATH` is created if it is not set.
+    ///
+    /// This fails if the directory contains the path separator of the platform (`;` on
+    /// Windows, `:` elsewhere), which cannot be represented in `PATH`.
+    pub fn prepend_path(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        // An empty entry would stand for the current directory, so none is added for an
+        // unset `PATH`.
+        let old = self
+            .env_store
+            .var_os("PATH")
+            .filter(|path| !path.is_empty());
+        let rest = old.iter().flat_map(env::split_paths);
+        let dirs = iter::once(dir.to_path_buf()).chain(rest);
+        let path = env::join_paths(dirs).map_err(|_| {
+            Error::msg(format!(
+                "cannot add `{}` to PATH: it contains the path separator",
+                dir.display()
+            ))
+        })?;
+
+        self.env_store.set_var("PATH", path);
+        Ok(())
+    }
+
+    /// Sets environment variables until the returned guard is dropped, which restores
+    /// their previous values and removes the ones that were not set before.
+    pub fn scoped_env(&mut self, vars: &[(&str, &str)]) -> EnvScope {
+        let saved = vars
+            .iter()
+            .map(|&(key, val)| {
+                let old = self.env_store.var_os(key);
+                self.env_store.set_var(key, val);
+                (OsString::from(key), old)
+            })
+            .collect();
+
+        EnvScope {
+            env_store: self.env_store.clone(),
+            saved,
+        }
+    }
+
+    /// Returns a copy of the shell with the environment variables set, e.g. for a single
+    /// command: `sh.with_env(&[("RUSTFLAGS", "-Dwarnings")]).cargo()`.
+    ///
+    /// The copy has its own (fake) environment, so neither shell sees the variables the
+    /// other sets afterwards, and the original is left untouched.
+    pub fn with_env(&self, vars: &[(&str, &str)]) -> Shell {
+        let env_store = self.env_store.to_fake();
+        for &(key, val) in vars {
+            env_store.set_var(key, val);
+        }
+        Shell {
+            env_store,
+            ..self.clone()
         }
     }
 
+    // ```
+    // $ echo "{{ s }}"
+    // ```
+    //
+    // Expands `$VAR` and `${VAR}`, and also `%VAR%` on Windows, from the shell's
+    // environment, e.g. in a tool path read from a config file. Unset variables are left
+    // as they are written.
+    pub fn expand<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        expand_vars(s, cfg!(windows), |key| {
+            self.env_store
+                .var_os(key)
+                .map(|val| val.to_string_lossy().into_owned())
+        })
+    }
+
     pub fn project_root(&self) -> &Path {
         &self.project_root
     }
 
+    /// Returns the working directory of subprocesses created afterwards, which is the
+    /// project root unless changed with `cd`.
+    pub fn current_dir(&self) -> &Path {
+        &self.cwd
+    }
+
+    // ```
+    // $ cd {{ dir }}
+    // ```
+    //
+    // Changes the default working directory of subprocesses created afterwards. A
+    // relative path is resolved against the current one, as is one passed to
+    // `Subprocess::current_dir` or `Subprocess::stdin_file`, or to the path queries
+    // (`exists`, `is_file`, `is_dir`, `metadata`, `file_size` and `canonicalize`), so
+    // that they look at the same files as the subprocesses. Other methods of the shell
+    // are not affected.
+    pub fn cd(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = self.cwd.join(dir);
+        let metadata = fs::metadata(&dir).map_err(|err| {
+            Error::io_context(err, format!("failed to cd into `{}`", dir.display()))
+        })?;
+        if !metadata.is_dir() {
+            return Err(Error::msg(format!(
+                "cannot cd into `{}`: not a directory",
+                dir.display()
+            )));
+        }
+
+        self.cwd = dir;
+        Ok(())
+    }
+
     pub fn target_dir(&self) -> &Path {
         &self.target_dir
     }
 
+    /// Returns the output directory of the given profile, e.g. `target/release`.
+    ///
+    /// As with cargo, the `dev` and `test` profiles are mapped to `debug`, and `bench` is
+    /// mapped to `release`.
+    pub fn profile_dir(&self, profile: &str) -> PathBuf {
+        let dir = match profile {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            profile => profile,
+        };
+        self.target_dir.join(dir)
+    }
+
+    /// Returns `path` relative to the project root, or unchanged if it lies outside of it.
+    ///
+    /// This is a purely lexical operation and does not touch the filesystem.
+    pub fn relative(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        path.strip_prefix(&self.project_root)
+            .unwrap_or(path)
+            .to_path_buf()
+    }
+
+    // ```
+    // $ realpath {{ path }}
+    // ```
+    //
+    // Resolves symlinks and `..` components, with a relative path taken relative to
+    // `current_dir`. The path must exist.
+    pub fn canonicalize(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = self.resolve(path.as_ref());
+        fs::canonicalize(&path).map_err(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                return Error::msg(format!(
+                    "cannot canonicalize `{}`: the path does not exist",
+                    path.display()
+                ));
+            }
+            Error::io_context(err, format!("failed to canonicalize `{}`", path.display()))
+        })
+    }
+
+    /// Resolves a relative path against `current_dir`, the same directory as the one
+    /// subprocesses are spawned in.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.cwd.join(path)
+    }
+
+    // ```
+    // $ test -e {{ path }}
+    // ```
+    pub fn exists(&self, path: impl AsRef<Path>) -> bool {
+        self.resolve(path.as_ref()).exists()
+    }
+
+    // ```
+    // $ test -f {{ path }}
+    // ```
+    pub fn is_file(&self, path: impl AsRef<Path>) -> bool {
+        self.resolve(path.as_ref()).is_file()
+    }
+
+    // ```
+    // $ test -d {{ path }}
+    // ```
+    pub fn is_dir(&self, path: impl AsRef<Path>) -> bool {
+        self.resolve(path.as_ref()).is_dir()
+    }
+
+    // ```
+    // $ stat {{ path }}
+    // ```
+    pub fn metadata(&self, path: impl AsRef<Path>) -> Result<fs::Metadata> {
+        let path = self.resolve(path.as_ref());
+        fs::metadata(&path)
+            .map_err(|err| Error::io_context(err, format!("failed to query `{}`", path.display())))
+    }
+
+    // ```
+    // $ stat -c %s {{ path }}
+    // ```
+    pub fn file_size(&self, path: impl AsRef<Path>) -> Result<u64> {
+        Ok(self.metadata(path)?.len())
+    }
+
     // ```
     // $ mkdir {{ path }} {{ flags }}
     // ```
+    //
+    // An existing directory is an error unless either `CreateFlags::EXIST_OK` or
+    // `CreateFlags::RECURSIVE` (which always tolerates it, like `mkdir -p`) is given.
     pub fn create_dir(&self, path: impl AsRef<Path>, flags: CreateFlags) -> Result<()> {
+        let path = path.as_ref();
+
         if flags.contains(CreateFlags::RECURSIVE) {
             fs::create_dir_all(path).map_err(Error::io_error)?;
         } else {
-            fs::create_dir(path).map_err(Error::io_error)?;
+            match fs::create_dir(path) {
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::AlreadyExists
+                        && flags.contains(CreateFlags::EXIST_OK)
+                        && path.is_dir() => {}
+                result => result.map_err(Error::io_error)?,
+            }
         }
         Ok(())
     }
@@ -116,129 +544,3387 @@ impl Shell {
     }
 
     // ```
-    // $ rm {{ path }} {{ flags }}
+    // $ printf %s {{ content }} | unix2dos > {{ path }}
     // ```
-    pub fn remove(&self, path: impl AsRef<Path>, flags: RemoveFlags) -> Result<()> {
+    //
+    // Writes the text with every line ending, whether LF or CRLF, converted to `eol`.
+    pub fn write_text(&self, path: impl AsRef<Path>, content: &str, eol: LineEnding) -> Result<()> {
         let path = path.as_ref();
+        let content = content.replace("\r\n", "\n");
+        let content = match eol {
+            LineEnding::Lf => content,
+            LineEnding::CrLf => content.replace('\n', "\r\n"),
+        };
 
-        if path.is_dir() {
-            if flags.contains(RemoveFlags::RECURSIVE) {
-                fs::remove_dir_all(path).map_err(Error::io_error)?;
-            } else {
-                fs::remove_dir(path).map_err(Error::io_error)?;
-            }
-
-            return Ok(());
-        }
+        fs::write(path, content)
+            .map_err(|err| Error::io_context(err, format!("failed to write `{}`", path.display())))
+    }
 
-        if path.is_file() {
-            fs::remove_file(path).map_err(Error::io_error)?;
+    // ```
+    // $ cmp -s {{ path }} - <<< {{ content }} || cat > {{ path }} <<< {{ content }}
+    // ```
+    //
+    // Writes the file only if its content differs, so that its modification time is left
+    // alone otherwise. Returns whether the file was written. A missing file counts as
+    // changed.
+    pub fn write_if_changed(
+        &self,
+        path: impl AsRef<Path>,
+        content: impl AsRef<[u8]>,
+    ) -> Result<bool> {
+        let (path, content) = (path.as_ref(), content.as_ref());
+        let context = || format!("failed to write `{}`", path.display());
 
-            return Ok(());
+        match fs::read(path) {
+            Ok(ref existing) if existing == content => return Ok(false),
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::io_context(err, context())),
         }
+        fs::write(path, content).map_err(|err| Error::io_context(err, context()))?;
+        Ok(true)
+    }
 
+    // ```
+    // $ for f in {{ files }}; do mkdir -p $(dirname $f) && cat > $f; done
+    // ```
+    //
+    // Writes each file in turn, creating its parent directories as needed, and stops at
+    // the first one that cannot be written.
+    pub fn write_all<I>(&self, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (PathBuf, Vec<u8>)>,
+    {
+        for (path, content) in files {
+            let context = || format!("failed to write `{}`", path.display());
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|err| Error::io_context(err, context()))?;
+            }
+            fs::write(&path, content).map_err(|err| Error::io_context(err, context()))?;
+        }
         Ok(())
     }
 
-    pub fn subprocess(&self, program: impl AsRef<OsStr>) -> Subprocess {
-        let dry_run = self.env_store.var_os("DRY_RUN").is_some();
+    // ```
+    // $ cat << EOF > {{ path }}.tmp && mv {{ path }}.tmp {{ path }}
+    // {{ content }}
+    // EOF
+    // ```
+    //
+    // Writes the content to a temporary file next to the target and renames it over the
+    // target, so that readers see either the old or the new content in full.
+    pub fn write_atomic(&self, path: impl AsRef<Path>, content: impl AsRef<[u8]>) -> Result<()> {
+        let path = path.as_ref();
+        let context = || format!("failed to write `{}`", path.display());
 
-        let mut command = Command::new(program);
-        command.current_dir(&self.project_root);
-        command.env_clear();
-        command.envs(self.env_store.vars_os());
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| Error::msg(format!("`{}` is not a file path", path.display())))?;
+        let mut temp_name = OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}.tmp", unique_name()));
+        let temp = path.with_file_name(temp_name);
 
-        command.stdin(Stdio::null());
-        command.stdout(Stdio::inherit());
-        command.stderr(Stdio::inherit());
+        // The replaced file keeps its permissions, e.g. when it is an executable script.
+        let result =
+            write_synced(&temp, content.as_ref()).and_then(|()| match fs::metadata(path) {
+                Ok(metadata) => fs::set_permissions(&temp, metadata.permissions()),
+                Err(_) => Ok(()),
+            });
+        let result = result.and_then(|()| match fs::rename(&temp, path) {
+            Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                eprintln!(
+                    "[cargo-xtask] warning: cannot replace `{}` atomically; writing it in place",
+                    path.display()
+                );
+                fs::write(path, content.as_ref())
+            }
+            result => result,
+        });
 
-        Subprocess { command, dry_run }
+        // The temporary file is only gone if it was renamed; it is unique to this call, so
+        // it can be removed unconditionally.
+        let _ = fs::remove_file(&temp);
+        result.map_err(|err| Error::io_context(err, context()))
     }
 
-    pub fn rustc(&self) -> Subprocess {
-        self.subprocess(
-            self.env_store
-                .var_os("RUSTC")
-                .or_else(|| option_env!("RUSTC").map(Into::into))
-                .unwrap_or_else(|| "rustc".into()),
-        )
+    // ```
+    // $ cat << EOF >> {{ path }}
+    // {{ content }}
+    // EOF
+    // ```
+    pub fn append(&self, path: impl AsRef<Path>, content: impl AsRef<[u8]>) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(Error::io_error)?;
+        file.write_all(content.as_ref()).map_err(Error::io_error)?;
+        Ok(())
     }
 
-    pub fn cargo(&self) -> Subprocess {
-        self.subprocess(
-            self.env_store
-                .var_os("CARGO")
-                .or_else(|| option_env!("CARGO").map(Into::into))
-                .unwrap_or_else(|| "cargo".into()),
-        )
+    // ```
+    // $ touch {{ path }}
+    // ```
+    pub fn touch(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|err| {
+                Error::io_context(err, format!("failed to touch `{}`", path.display()))
+            })?;
+
+        match file.set_modified(SystemTime::now()) {
+            Err(ref err) if err.kind() == io::ErrorKind::Unsupported => Ok(()),
+            result => result.map_err(|err| {
+                Error::io_context(err, format!("failed to touch `{}`", path.display()))
+            }),
+        }
     }
-}
 
-bitflags! {
-    pub struct CreateFlags: u32 {
-        const RECURSIVE = 0b_0000_0001;
+    // ```
+    // $ cat {{ path }}
+    // ```
+    pub fn read(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let path = path.as_ref();
+        fs::read(path)
+            .map_err(|err| Error::io_context(err, format!("failed to read `{}`", path.display())))
     }
-}
 
-bitflags! {
-    pub struct RemoveFlags: u32 {
-        const RECURSIVE = 0b_0000_0001;
+    // ```
+    // $ cat {{ path }}
+    // ```
+    pub fn read_to_string(&self, path: impl AsRef<Path>) -> Result<String> {
+        let path = path.as_ref();
+        String::from_utf8(self.read(path)?)
+            .map_err(|_| Error::msg(format!("`{}` is not valid UTF-8", path.display())))
     }
-}
 
-/// A thin wrapper to improve the convenience of `std::process::Command`.
-pub struct Subprocess {
-    command: Command,
-    dry_run: bool,
-}
+    // ```
+    // $ sha256sum {{ path }}
+    // ```
+    //
+    // Returns the SHA-256 digest of the file as lowercase hex. The file is streamed
+    // rather than loaded into memory. Requires the `sha2` feature.
+    #[cfg(feature = "sha2")]
+    pub fn sha256(&self, path: impl AsRef<Path>) -> Result<String> {
+        use sha2::Digest;
+        use std::fmt::Write as _;
 
-impl Subprocess {
-    pub fn arg<S>(mut self, arg: S) -> Self
-    where
-        S: AsRef<OsStr>,
-    {
-        self.command.arg(arg);
-        self
+        let path = path.as_ref();
+        let context = || format!("failed to hash `{}`", path.display());
+        let mut file = fs::File::open(path).map_err(|err| Error::io_context(err, context()))?;
+        let mut hasher = sha2::Sha256::new();
+        io::copy(&mut file, &mut hasher).map_err(|err| Error::io_context(err, context()))?;
+
+        let mut digest = String::with_capacity(64);
+        for byte in hasher.finalize() {
+            let _ = write!(digest, "{:02x}", byte);
+        }
+        Ok(digest)
     }
 
-    pub fn args<I, S>(mut self, args: I) -> Self
-    where
-        I: IntoIterator<Item = S>,
-        S: AsRef<OsStr>,
-    {
-        self.command.args(args);
-        self
+    // ```
+    // $ diff -u - {{ path }} <<< {{ expected }}
+    // ```
+    //
+    // Checks that the file contains exactly `expected`, e.g. in golden tests. On mismatch,
+    // the error shows a unified diff with the `similar` feature, and both contents
+    // otherwise.
+    pub fn assert_file_eq(&self, path: impl AsRef<Path>, expected: &str) -> Result<()> {
+        let path = path.as_ref();
+        let actual = self.read_to_string(path)?;
+        if actual == expected {
+            return Ok(());
+        }
+
+        #[cfg(feature = "similar")]
+        let details = similar::TextDiff::from_lines(expected, &actual)
+            .unified_diff()
+            .header("expected", &path.display().to_string())
+            .to_string();
+        #[cfg(not(feature = "similar"))]
+        let details = format!("expected:\n{}\nactual:\n{}", expected, actual);
+
+        Err(Error::msg(format!(
+            "`{}` does not have the expected content\n{}",
+            path.display(),
+            details
+        )))
     }
 
-    pub fn env<K, V>(mut self, key: K, val: V) -> Self
-    where
-        K: AsRef<OsStr>,
-        V: AsRef<OsStr>,
-    {
-        self.command.env(key, val);
-        self
+    // ```
+    // $ cat {{ path }} 2>/dev/null
+    // ```
+    //
+    // Like `read_to_string`, but returns `None` if the file does not exist.
+    pub fn read_to_string_opt(&self, path: impl AsRef<Path>) -> Result<Option<String>> {
+        let path = path.as_ref();
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(Error::io_context(
+                    err,
+                    format!("failed to read `{}`", path.display()),
+                ))
+            }
+        };
+        String::from_utf8(content)
+            .map(Some)
+            .map_err(|_| Error::msg(format!("`{}` is not valid UTF-8", path.display())))
     }
 
-    pub fn silent(mut self) -> Self {
-        self.command.stdout(Stdio::null());
-        self.command.stderr(Stdio::null());
-        self
+    // ```
+    // $ ls {{ path }}
+    // ```
+    //
+    // Returns the immediate children of the directory, sorted by file name.
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        let mut entries = self.read_dir_iter(path)?.collect::<Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        Ok(entries)
     }
 
-    pub fn run(mut self) -> Result<()> {
-        if self.dry_run {
-            eprintln!("[cargo-xtask] - skipped");
-            return Ok(());
-        }
+    // ```
+    // $ ls -U {{ path }}
+    // ```
+    //
+    // Like `read_dir`, but yields the children lazily in the order returned by the OS.
+    pub fn read_dir_iter(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<impl Iterator<Item = Result<PathBuf>>> {
+        let path = path.as_ref().to_path_buf();
+        let entries = fs::read_dir(&path).map_err(|err| read_dir_error(err, &path))?;
+        Ok(entries.map(move |entry| {
+            entry
+                .map(|entry| entry.path())
+                .map_err(|err| read_dir_error(err, &path))
+        }))
+    }
 
-        let st = self.command.status().map_err(Error::io_error)?;
-        if !st.success() {
-            return Err(Error::msg(format!(
-                "Subprocess failed with the exit code {}",
-                st.code().unwrap_or(0),
-            )));
-        }
+    // ```
+    // $ find {{ root }} -mindepth 1 {{ flags }}
+    // ```
+    //
+    // Walks the directory tree depth-first, yielding every file and directory below
+    // `root` (but not `root` itself), each directory before its contents. Symlinks are
+    // not followed unless `WalkFlags::FOLLOW_LINKS` is given, in which case directories
+    // reached twice are reported as errors instead of being walked again.
+    //
+    // Errors while reading a directory are yielded in place of its contents, right after
+    // the directory itself, and the walk carries on with the remaining entries.
+    pub fn walk(
+        &self,
+        root: impl AsRef<Path>,
+        flags: WalkFlags,
+    ) -> Result<impl Iterator<Item = Result<PathBuf>>> {
+        let root = root.as_ref();
+        let mut walk = Walk {
+            stack: vec![],
+            follow_links: flags.contains(WalkFlags::FOLLOW_LINKS),
+            visited: HashSet::new(),
+            pending: None,
+        };
+        walk.descend(root)?;
+        Ok(walk)
+    }
 
-        Ok(())
+    // ```
+    // $ git ls-files {{ root }}
+    // ```
+    //
+    // Like `walk`, but skips the paths excluded by `.gitignore` files in the tree as well
+    // as the `.git` directories, and returns the paths sorted. This requires the `ignore`
+    // feature; without it, nothing is skipped and this behaves like a sorted `walk`.
+    pub fn walk_tracked(&self, root: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        let root = root.as_ref();
+
+        #[cfg(feature = "ignore")]
+        let mut paths = {
+            let walker = ignore::WalkBuilder::new(root)
+                .standard_filters(false)
+                .git_ignore(true)
+                .parents(false)
+                .require_git(false)
+                .filter_entry(|entry| entry.file_name() != ".git")
+                .build();
+
+            let mut paths = vec![];
+            for entry in walker {
+                let entry = entry.map_err(|err| {
+                    Error::msg(format!("failed to walk `{}`: {}", root.display(), err))
+                })?;
+                if entry.depth() > 0 {
+                    paths.push(entry.into_path());
+                }
+            }
+            paths
+        };
+        #[cfg(not(feature = "ignore"))]
+        let mut paths = self
+            .walk(root, WalkFlags::empty())?
+            .collect::<Result<Vec<_>>>()?;
+
+        paths.sort();
+        Ok(paths)
+    }
+
+    // ```
+    // $ ls -d {{ pattern }}
+    // ```
+    //
+    // Resolves a shell-style glob (`*`, `**` and `?`) relative to the project root and
+    // returns the matched paths in sorted order.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>> {
+        let absolute = if Path::new(pattern).is_absolute() {
+            pattern.to_owned()
+        } else {
+            let root = glob::Pattern::escape(&self.project_root.to_string_lossy());
+            format!("{}/{}", root.trim_end_matches('/'), pattern)
+        };
+
+        let paths = glob::glob(&absolute).map_err(|err| {
+            Error::msg(format!("invalid glob pattern `{}`: {}", pattern, err.msg))
+        })?;
+
+        let mut matches = paths
+            .map(|path| {
+                path.map_err(|err| {
+                    let context = format!("failed to read `{}`", err.path().display());
+                    Error::io_context(err.into(), context)
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        matches.sort();
+        Ok(matches)
+    }
+
+    // ```
+    // $ cargo metadata --no-deps --format-version 1 | jq -r '.workspace_members[]'
+    // ```
+    //
+    // Returns the directory of each member of the workspace at the project root, in the
+    // order of the `members` array, with globs resolved and `exclude` applied. The root
+    // itself is included first if it is also a package, and is the only member if the
+    // manifest has no `[workspace]`.
+    pub fn workspace_members(&self) -> Result<Vec<PathBuf>> {
+        let manifest_path = self.project_root.join("Cargo.toml");
+        let manifest = self.read_to_string(&manifest_path)?;
+        let malformed = |reason: &dyn fmt::Display| {
+            Error::msg(format!(
+                "malformed manifest `{}`: {}",
+                manifest_path.display(),
+                reason
+            ))
+        };
+        let manifest: toml::Table = manifest.parse().map_err(|err| malformed(&err))?;
+
+        let mut members = Vec::new();
+        if manifest.contains_key("package") {
+            members.push(self.project_root.clone());
+        }
+        let workspace = match manifest.get("workspace") {
+            Some(toml::Value::Table(workspace)) => workspace,
+            Some(_) => return Err(malformed(&"`workspace` is not a table")),
+            None => return Ok(members),
+        };
+        let paths = |key: &str| -> Result<Vec<&str>> {
+            match workspace.get(key) {
+                Some(toml::Value::Array(values)) => values
+                    .iter()
+                    .map(|value| {
+                        value.as_str().ok_or_else(|| {
+                            malformed(&format_args!(
+                                "`workspace.{}` must only contain strings",
+                                key
+                            ))
+                        })
+                    })
+                    .collect(),
+                Some(_) => Err(malformed(&format_args!(
+                    "`workspace.{}` is not an array",
+                    key
+                ))),
+                None => Ok(Vec::new()),
+            }
+        };
+
+        let excluded: Vec<PathBuf> = paths("exclude")?
+            .into_iter()
+            .map(|path| self.project_root.join(path))
+            .collect();
+        for pattern in paths("members")? {
+            let matches = self.glob(pattern)?;
+            if matches.is_empty() {
+                return Err(malformed(&format_args!(
+                    "workspace member `{}` does not exist",
+                    pattern
+                )));
+            }
+            for dir in matches {
+                if !dir.is_dir() || excluded.iter().any(|path| dir.starts_with(path)) {
+                    continue;
+                }
+                if !dir.join("Cargo.toml").is_file() {
+                    return Err(malformed(&format_args!(
+                        "workspace member `{}` has no `Cargo.toml`",
+                        dir.display()
+                    )));
+                }
+                if !members.contains(&dir) {
+                    members.push(dir);
+                }
+            }
+        }
+        Ok(members)
+    }
+
+    // ```
+    // $ cp {{ from }} {{ to }} {{ flags }}
+    // ```
+    pub fn copy(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+        flags: CopyFlags,
+    ) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let context = || format!("failed to copy `{}` to `{}`", from.display(), to.display());
+
+        let metadata = fs::metadata(from).map_err(|err| Error::io_context(err, context()))?;
+        if metadata.is_dir() {
+            if !flags.contains(CopyFlags::RECURSIVE) {
+                return Err(Error::msg(format!(
+                    "`{}` is a directory; use `CopyFlags::RECURSIVE` to copy it",
+                    from.display()
+                )));
+            }
+            copy_tree(from, to, &mut |_| {}).map_err(|err| Error::io_context(err, context()))?;
+        } else {
+            fs::copy(from, to).map_err(|err| Error::io_context(err, context()))?;
+        }
+
+        Ok(())
+    }
+
+    // ```
+    // $ cp {{ file }} {{ dir }}/
+    // ```
+    //
+    // Copies the file into the directory under the same name, and returns the path of the
+    // copy.
+    pub fn copy_into(&self, file: impl AsRef<Path>, dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let (file, dir) = (file.as_ref(), dir.as_ref());
+        if !dir.is_dir() {
+            return Err(Error::msg(format!(
+                "cannot copy `{}` into `{}`: not a directory",
+                file.display(),
+                dir.display()
+            )));
+        }
+        let name = file
+            .file_name()
+            .ok_or_else(|| Error::msg(format!("`{}` has no file name", file.display())))?;
+
+        let to = dir.join(name);
+        fs::copy(file, &to).map_err(|err| {
+            Error::io_context(
+                err,
+                format!("failed to copy `{}` to `{}`", file.display(), to.display()),
+            )
+        })?;
+        Ok(to)
+    }
+
+    // ```
+    // $ cp -R {{ from }} {{ to }}
+    // ```
+    //
+    // Copies the directory tree and returns the number of files copied. Symlinks are
+    // recreated as symlinks rather than followed, so link cycles are never traversed.
+    pub fn copy_dir(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<u64> {
+        self.copy_dir_with_progress(from, to, |_| {})
+    }
+
+    // ```
+    // $ cp -Rv {{ from }} {{ to }}
+    // ```
+    //
+    // Like `copy_dir`, but calls `f` with the source path of each file (or symlink) once
+    // it has been copied.
+    pub fn copy_dir_with_progress(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+        mut f: impl FnMut(&Path),
+    ) -> Result<u64> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+
+        if !from.is_dir() {
+            return Err(Error::msg(format!(
+                "`{}` is not a directory",
+                from.display()
+            )));
+        }
+
+        copy_tree(from, to, &mut f).map_err(|err| {
+            Error::io_context(
+                err,
+                format!("failed to copy `{}` to `{}`", from.display(), to.display()),
+            )
+        })
+    }
+
+    // ```
+    // $ mv {{ from }} {{ to }}
+    // ```
+    //
+    // Falls back to copying and then removing the source when the paths are on
+    // different filesystems.
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+
+        move_path(from, to).map_err(|err| {
+            Error::io_context(
+                err,
+                format!("failed to move `{}` to `{}`", from.display(), to.display()),
+            )
+        })
+    }
+
+    // ```
+    // $ chmod +x {{ path }}
+    // ```
+    //
+    // This is a no-op on platforms without Unix permissions, apart from checking that
+    // the file exists.
+    pub fn set_executable(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path).map_err(|err| permissions_error(err, path))?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata.permissions().mode() | 0o111;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .map_err(|err| permissions_error(err, path))?;
+        }
+        #[cfg(not(unix))]
+        let _ = metadata;
+
+        Ok(())
+    }
+
+    // ```
+    // $ chmod {{ mode }} {{ path }}
+    // ```
+    //
+    // On platforms without Unix permissions, only the write bits are taken into
+    // account to toggle the read-only flag.
+    pub fn set_permissions(&self, path: impl AsRef<Path>, mode: u32) -> Result<()> {
+        let path = path.as_ref();
+
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            fs::Permissions::from_mode(mode)
+        };
+        #[cfg(not(unix))]
+        let permissions = {
+            let mut permissions = fs::metadata(path)
+                .map_err(|err| permissions_error(err, path))?
+                .permissions();
+            permissions.set_readonly(mode & 0o222 == 0);
+            permissions
+        };
+
+        fs::set_permissions(path, permissions).map_err(|err| permissions_error(err, path))
+    }
+
+    // ```
+    // $ ln -s {{ original }} {{ link }}
+    // ```
+    pub fn symlink(&self, original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+        let (original, link) = (original.as_ref(), link.as_ref());
+
+        symlink(original, link).map_err(|err| {
+            if err.kind() == io::ErrorKind::AlreadyExists {
+                return Error::msg(format!(
+                    "cannot create symlink `{}`: the path already exists",
+                    link.display()
+                ));
+            }
+            Error::io_context(
+                err,
+                format!(
+                    "failed to create symlink `{}` pointing to `{}`",
+                    link.display(),
+                    original.display()
+                ),
+            )
+        })
+    }
+
+    // ```
+    // $ ln {{ original }} {{ link }}
+    // ```
+    //
+    // Only files can be hard-linked, not directories, and both paths must be on the
+    // same filesystem.
+    pub fn hard_link(&self, original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+        let (original, link) = (original.as_ref(), link.as_ref());
+
+        fs::hard_link(original, link).map_err(|err| {
+            if err.kind() == io::ErrorKind::CrossesDevices {
+                return Error::msg(format!(
+                    "cannot create hard link `{}` pointing to `{}`: the paths are on different filesystems",
+                    link.display(),
+                    original.display()
+                ));
+            }
+            Error::io_context(
+                err,
+                format!(
+                    "failed to create hard link `{}` pointing to `{}`",
+                    link.display(),
+                    original.display()
+                ),
+            )
+        })
+    }
+
+    // ```
+    // $ rm {{ path }} {{ flags }}
+    // ```
+    /// A missing path is an error unless `RemoveFlags::MISSING_OK` is given.
+    ///
+    /// The path is not inspected beforehand, so that another process deleting it
+    /// concurrently just results in the same outcome as a missing path.
+    ///
+    /// With `RemoveFlags::BACKUP`, the path is moved to the `.xtask-trash` directory in
+    /// `target_dir` under a timestamped name instead. The trash is never emptied automatically, so
+    /// it keeps growing until it is deleted by hand.
+    pub fn remove(&self, path: impl AsRef<Path>, flags: RemoveFlags) -> Result<()> {
+        let path = path.as_ref();
+        if flags.contains(RemoveFlags::BACKUP) {
+            return self.move_to_trash(path, flags);
+        }
+
+        // Only if the path cannot be removed as a file, look whether it is a directory.
+        let result = match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                #[cfg(test)]
+                tests::before_inspect(path);
+                match fs::symlink_metadata(path) {
+                    Ok(ref metadata) if metadata.is_dir() => {
+                        if flags.contains(RemoveFlags::RECURSIVE) {
+                            fs::remove_dir_all(path)
+                        } else {
+                            fs::remove_dir(path)
+                        }
+                    }
+                    Err(gone) if gone.kind() == io::ErrorKind::NotFound => Err(gone),
+                    _ => Err(err),
+                }
+            }
+            result => result,
+        };
+
+        match result {
+            Err(ref err)
+                if err.kind() == io::ErrorKind::NotFound
+                    && flags.contains(RemoveFlags::MISSING_OK) =>
+            {
+                Ok(())
+            }
+            result => result.map_err(|err| {
+                Error::io_context(err, format!("failed to remove `{}`", path.display()))
+            }),
+        }
+    }
+
+    fn move_to_trash(&self, path: &Path, flags: RemoveFlags) -> Result<()> {
+        let context = || format!("failed to move `{}` to the trash", path.display());
+
+        let metadata = match fs::symlink_metadata(path) {
+            Err(ref err)
+                if err.kind() == io::ErrorKind::NotFound
+                    && flags.contains(RemoveFlags::MISSING_OK) =>
+            {
+                return Ok(())
+            }
+            result => result.map_err(|err| Error::io_context(err, context()))?,
+        };
+        if metadata.is_dir() && !flags.contains(RemoveFlags::RECURSIVE) {
+            let mut entries = fs::read_dir(path).map_err(|err| read_dir_error(err, path))?;
+            if entries.next().is_some() {
+                return Err(Error::io_context(
+                    io::ErrorKind::DirectoryNotEmpty.into(),
+                    context(),
+                ));
+            }
+        }
+
+        // The real locations are compared, since either path may be relative or contain
+        // `..` and symlinks. The last component of `path` is not followed, as a symlink
+        // would be moved rather than its target.
+        let trash = self.target_dir.join(".xtask-trash");
+        let real_path = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => canonicalize_existing(parent).join(name),
+            _ => canonicalize_existing(path),
+        };
+        if canonicalize_existing(&trash).starts_with(&real_path) {
+            return Err(Error::msg(format!(
+                "cannot move `{}` to the trash inside it",
+                path.display()
+            )));
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut name = OsString::from(format!("{}-{}-", timestamp, unique_name()));
+        name.push(path.file_name().unwrap_or_else(|| OsStr::new("root")));
+        let dest = trash.join(name);
+
+        let result = fs::create_dir_all(&trash).and_then(|()| match fs::rename(path, &dest) {
+            Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                if metadata.is_dir() {
+                    copy_tree(path, &dest, &mut |_| {})?;
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::copy(path, &dest)?;
+                    fs::remove_file(path)
+                }
+            }
+            result => result,
+        });
+        result.map_err(|err| Error::io_context(err, context()))
+    }
+
+    // ```
+    // $ find {{ path }} -mindepth 1 -delete
+    // ```
+    //
+    // Removes everything inside the directory, but keeps the directory itself along with
+    // its permissions.
+    pub fn clear_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(Error::msg(format!(
+                "`{}` is not a directory",
+                path.display()
+            )));
+        }
+
+        for entry in self.read_dir_iter(path)? {
+            self.remove(entry?, RemoveFlags::RECURSIVE | RemoveFlags::MISSING_OK)?;
+        }
+        Ok(())
+    }
+
+    // ```
+    // $ which {{ program }}
+    // ```
+    //
+    // Looks up the program in the `PATH` of the shell's environment without spawning
+    // any process. On Windows, the extensions listed in `PATHEXT` are tried as well.
+    pub fn which(&self, program: impl AsRef<OsStr>) -> Option<PathBuf> {
+        let program = Path::new(program.as_ref());
+
+        if program.components().count() > 1 {
+            return self
+                .executable_candidates(program)
+                .into_iter()
+                .find(|path| is_executable(path));
+        }
+
+        let paths = self.env_store.var_os("PATH")?;
+        env::split_paths(&paths)
+            .flat_map(|dir| self.executable_candidates(&dir.join(program)))
+            .find(|path| is_executable(path))
+    }
+
+    #[cfg(not(windows))]
+    fn executable_candidates(&self, path: &Path) -> Vec<PathBuf> {
+        vec![path.to_path_buf()]
+    }
+
+    #[cfg(windows)]
+    fn executable_candidates(&self, path: &Path) -> Vec<PathBuf> {
+        let pathext = self
+            .env_store
+            .var_os("PATHEXT")
+            .unwrap_or_else(|| ".COM;.EXE;.BAT;.CMD".into());
+
+        let mut candidates = vec![path.to_path_buf()];
+        candidates.extend(
+            pathext
+                .to_string_lossy()
+                .split(';')
+                .filter(|ext| !ext.is_empty())
+                .map(|ext| {
+                    let mut candidate = path.as_os_str().to_owned();
+                    candidate.push(ext);
+                    PathBuf::from(candidate)
+                }),
+        );
+        candidates
+    }
+
+    // ```
+    // $ mkdir -p {{ path }}
+    // ```
+    //
+    // Creates the directory along with its parents, unless it already exists. Unlike
+    // `create_dir`, a file in its place is reported as such. In dry-run mode, the
+    // directory is only printed.
+    pub fn ensure_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        match fs::metadata(path) {
+            Ok(ref metadata) if metadata.is_dir() => return Ok(()),
+            Ok(_) => {
+                return Err(Error::msg(format!(
+                    "cannot create directory `{}`: a file exists at that path",
+                    path.display()
+                )))
+            }
+            Err(_) => {}
+        }
+
+        let command_line = || format!("mkdir -p {}", quote(path.as_os_str(), self.quoting));
+        if self.announce(command_line) {
+            return Ok(());
+        }
+        fs::create_dir_all(path)
+            .map_err(|err| Error::io_context(err, format!("failed to create `{}`", path.display())))
+    }
+
+    // ```
+    // $ curl -fsSL -o {{ dest }} {{ url }}
+    // ```
+    //
+    // The body is written with `write_atomic`, so `dest` is never left half-downloaded.
+    #[cfg(feature = "ureq")]
+    pub fn download(&self, url: &str, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        let command_line = || {
+            format!(
+                "curl -fsSL -o {} {}",
+                quote(dest.as_os_str(), self.quoting),
+                quote(OsStr::new(url), self.quoting)
+            )
+        };
+        if self.announce(command_line) {
+            return Ok(());
+        }
+
+        let response = match ureq::get(url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, response)) => {
+                return Err(Error::msg(format!(
+                    "failed to download `{}`: HTTP {} {}",
+                    url,
+                    code,
+                    response.status_text()
+                )))
+            }
+            Err(ureq::Error::Transport(err)) => {
+                let mut message = format!("failed to download `{}`: {}", url, err.kind());
+                if let Some(details) = err.message() {
+                    message.push_str(": ");
+                    message.push_str(details);
+                }
+                return Err(Error::msg(message));
+            }
+        };
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .map_err(|err| Error::io_context(err, format!("failed to download `{}`", url)))?;
+        self.write_atomic(dest, body)
+    }
+
+    // ```
+    // $ rm -rf {{ target_dir }}/*
+    // ```
+    //
+    // Removes everything inside `target_dir`, leaving an empty directory behind. As a
+    // guard against misconfiguration, this refuses to run if `target_dir` is the project
+    // root or one of its ancestors. In dry-run mode, the entries are only printed.
+    pub fn clean_target(&self) -> Result<()> {
+        let target_dir = &self.target_dir;
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        if canonical(&self.project_root).starts_with(canonical(target_dir)) {
+            return Err(Error::msg(format!(
+                "refusing to clean `{}`: it contains the project root",
+                target_dir.display()
+            )));
+        }
+
+        let entries = match self.read_dir(target_dir) {
+            Ok(entries) => entries,
+            Err(_) if !target_dir.exists() => vec![],
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let command_line = || format!("rm -rf {}", quote(entry.as_os_str(), self.quoting));
+            if self.announce(command_line) {
+                continue;
+            }
+            self.remove(&entry, RemoveFlags::RECURSIVE | RemoveFlags::MISSING_OK)?;
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+        self.create_dir(target_dir, CreateFlags::RECURSIVE | CreateFlags::EXIST_OK)
+    }
+
+    // ```
+    // $ mktemp -d -p {{ target_dir }}/tmp
+    // ```
+    //
+    // Creates a uniquely named directory under `target_dir`, which is removed along with
+    // its contents when the returned guard is dropped (unless in dry-run mode).
+    pub fn temp_dir(&self) -> Result<TempDir> {
+        let parent = self.target_dir.join("tmp");
+        fs::create_dir_all(&parent).map_err(|err| {
+            Error::io_context(err, format!("failed to create `{}`", parent.display()))
+        })?;
+
+        loop {
+            let path = parent.join(unique_name());
+            match fs::create_dir(&path) {
+                Ok(()) => {
+                    return Ok(TempDir {
+                        path,
+                        keep: self.dry_run,
+                    })
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    return Err(Error::io_context(
+                        err,
+                        format!("failed to create `{}`", path.display()),
+                    ))
+                }
+            }
+        }
+    }
+
+    // ```
+    // $ mktemp -p {{ target_dir }}/tmp --suffix {{ suffix }}
+    // ```
+    //
+    // Creates a uniquely named empty file under `target_dir`, ending with `suffix`, which
+    // is removed when the returned guard is dropped (unless in dry-run mode).
+    pub fn temp_file(&self, suffix: &str) -> Result<TempFile> {
+        let parent = self.target_dir.join("tmp");
+        fs::create_dir_all(&parent).map_err(|err| {
+            Error::io_context(err, format!("failed to create `{}`", parent.display()))
+        })?;
+
+        loop {
+            let path = parent.join(format!("{}{}", unique_name(), suffix));
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => {
+                    return Ok(TempFile {
+                        path,
+                        keep: self.dry_run,
+                    })
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    return Err(Error::io_context(
+                        err,
+                        format!("failed to create `{}`", path.display()),
+                    ))
+                }
+            }
+        }
+    }
+
+    pub fn subprocess(&self, program: impl AsRef<OsStr>) -> Subprocess {
+        let mut command = Command::new(program);
+        command.current_dir(&self.cwd);
+
+        command.stdin(Stdio::null());
+        command.stdout(Stdio::inherit());
+        command.stderr(Stdio::inherit());
+
+        self.wrap(command, false)
+    }
+
+    /// Turns a `Command` prepared by the caller, e.g. with `pre_exec`, into a subprocess
+    /// that follows the dry-run, verbose and other settings of the shell.
+    ///
+    /// Nothing the caller may have configured is overridden: the standard streams keep
+    /// the defaults of `Command` unless set, and the environment is the one of the
+    /// command (see `Subprocess::inherit_env` to apply the shell's environment beneath
+    /// the variables set on it). Only an unset working directory defaults to
+    /// `current_dir`.
+    pub fn wrap_command(&self, mut command: Command) -> Subprocess {
+        if command.get_current_dir().is_none() {
+            command.current_dir(&self.cwd);
+        }
+        self.wrap(command, true)
+    }
+
+    fn wrap(&self, command: Command, inherit_env: bool) -> Subprocess {
+        Subprocess {
+            command,
+            dry_run: self.dry_run,
+            verbose: self.verbose,
+            color: self.use_color(),
+            quoting: self.quoting,
+            dry_run_log: self.dry_run_log.clone(),
+            observer: self.observer.clone(),
+            project_root: self.project_root.clone(),
+            cwd: self.cwd.clone(),
+            base_env: self.captured_env(),
+            inherit_env,
+            silent: false,
+            buffered: false,
+            stdin: None,
+            timeout: None,
+            retry: None,
+            kill_on_drop: false,
+        }
+    }
+
+    // ```
+    // $ {{ program }} {{ args }}
+    // ```
+    //
+    // A shorthand for `self.subprocess(program).args(args).run()`.
+    pub fn run<I, S>(&self, program: impl AsRef<OsStr>, args: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.subprocess(program).args(args).run()
+    }
+
+    // ```
+    // $ {{ command }} || exit $?
+    // ```
+    //
+    // Runs the subprocess, and on failure reports the error and exits the current process
+    // with the exit code of the subprocess, or with 1 if it has none (e.g. it could not be
+    // started or was killed by a signal).
+    //
+    // Meant for the `main` of an xtask binary; use `Subprocess::run` to handle the error.
+    pub fn run_or_exit(&self, subprocess: Subprocess) {
+        if let Err(err) = subprocess.run() {
+            eprintln!("[cargo-xtask] error: {}", err);
+            process::exit(err.exit_code().unwrap_or(1));
+        }
+    }
+
+    // ```
+    // $ {{ step }} && {{ step }} && ...
+    // ```
+    //
+    // Creates an empty sequence of commands, each of which only runs if the previous one
+    // succeeded.
+    pub fn sequence(&self) -> Sequence {
+        Sequence {
+            steps: vec![],
+            dry_run: self.dry_run,
+            verbose: self.verbose,
+            color: self.use_color(),
+            dry_run_log: self.dry_run_log.clone(),
+        }
+    }
+
+    // Picks the program named by `var` in the environment, or else at build time.
+    fn toolchain_program(&self, var: &str, built_with: Option<&str>, default: &str) -> OsString {
+        let program = self
+            .env_store
+            .var_os(var)
+            .or_else(|| built_with.map(Into::into))
+            .unwrap_or_else(|| default.into());
+        with_exe_extension(program)
+    }
+
+    /// Echoes a file operation as the equivalent command, and reports whether it must be
+    /// skipped. Unlike a subprocess, it is echoed in dry-run mode even if not verbose,
+    /// unless `set_dry_run_echo(false)` was called.
+    fn announce(&self, command_line: impl FnOnce() -> String) -> bool {
+        announce(
+            self.verbose || (self.dry_run && self.dry_run_log.echo),
+            self.use_color(),
+            self.dry_run,
+            &self.dry_run_log,
+            command_line,
+        )
+    }
+
+    fn captured_env(&self) -> Vec<(OsString, OsString)> {
+        // The store has no particular order; sort it so that every run passes the
+        // variables to the subprocess in the same order.
+        let mut vars: Vec<_> = self.env_store.vars_os().collect();
+        vars.sort();
+        vars
+    }
+
+    pub fn rustc(&self) -> Subprocess {
+        self.subprocess(self.toolchain_program("RUSTC", option_env!("RUSTC"), "rustc"))
+    }
+
+    // Runs cargo with `CARGO_TERM_COLOR` set according to `set_color`, so that its
+    // diagnostics stay colored when stderr is a terminal even if its output is captured
+    // or buffered. Pass `ColorChoice::Never` to disable this, or override the variable
+    // with `Subprocess::env`.
+    //
+    // The variable is used rather than `--color`, which would have to come after the
+    // subcommand and could not be combined with a `+toolchain` argument.
+    pub fn cargo(&self) -> Subprocess {
+        let cargo = self.subprocess(self.toolchain_program("CARGO", option_env!("CARGO"), "cargo"));
+
+        match (self.color, self.use_color()) {
+            (ColorChoice::Never, _) => cargo.env("CARGO_TERM_COLOR", "never"),
+            (_, true) => cargo.env("CARGO_TERM_COLOR", "always"),
+            (_, false) => cargo,
+        }
+    }
+
+    // ```
+    // $ cargo build
+    // ```
+    pub fn cargo_build(&self) -> Subprocess {
+        self.cargo().arg("build")
+    }
+
+    // ```
+    // $ cargo test
+    // ```
+    pub fn cargo_test(&self) -> Subprocess {
+        self.cargo().arg("test")
+    }
+}
+
+/// Looks for the nearest directory above the manifest directory whose `Cargo.toml`
+/// declares a `[workspace]`. Manifests that cannot be read or parsed are skipped.
+fn find_workspace_root(manifest_dir: &Path) -> Option<&Path> {
+    manifest_dir.ancestors().skip(1).find(|dir| {
+        fs::read_to_string(dir.join("Cargo.toml"))
+            .ok()
+            .and_then(|manifest| manifest.parse::<toml::Table>().ok())
+            .is_some_and(|manifest| manifest.contains_key("workspace"))
+    })
+}
+
+/// Canonicalizes the longest prefix of `path` that exists, and appends the rest as it is.
+fn canonicalize_existing(path: &Path) -> PathBuf {
+    let mut rest = vec![];
+    let mut current = path;
+    loop {
+        let dir = if current.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            current
+        };
+        if let Ok(real) = fs::canonicalize(dir) {
+            return rest.iter().rev().fold(real, |real, name| real.join(name));
+        }
+        match (current.parent(), current.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                current = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+fn write_synced(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Replaces `$VAR`, `${VAR}` and, if `percent` is set, `%VAR%` with the values returned by
+/// `lookup`, keeping the references it returns `None` for.
+fn expand_vars(s: &str, percent: bool, lookup: impl Fn(&str) -> Option<String>) -> Cow<'_, str> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::new();
+    let mut copied = 0;
+    let mut rest = s;
+
+    while let Some(start) = rest.find(|c| c == '$' || (percent && c == '%')) {
+        let offset = s.len() - rest.len() + start;
+        let after = &rest[start + 1..];
+        // The name of the variable, and the length of the whole reference.
+        let reference = match (rest.as_bytes()[start], after.strip_prefix('{')) {
+            (b'$', Some(braced)) => braced.find('}').map(|end| (&braced[..end], end + 3)),
+            (b'$', None) => {
+                let end = after.find(|c| !is_name(c)).unwrap_or(after.len());
+                Some((&after[..end], end + 1))
+            }
+            _ => after.find('%').map(|end| (&after[..end], end + 2)),
+        };
+
+        match reference {
+            Some((name, len)) if !name.is_empty() => match lookup(name) {
+                Some(val) => {
+                    expanded.push_str(&s[copied..offset]);
+                    expanded.push_str(&val);
+                    copied = offset + len;
+                    rest = &s[copied..];
+                }
+                None => rest = &s[offset + 1..],
+            },
+            _ => rest = &s[offset + 1..],
+        }
+    }
+
+    if copied == 0 {
+        return Cow::Borrowed(s);
+    }
+    expanded.push_str(&s[copied..]);
+    Cow::Owned(expanded)
+}
+
+/// Generates a name that is unique across processes and threads.
+fn unique_name() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "{}-{}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn read_dir_error(err: io::Error, path: &Path) -> Error {
+    Error::io_context(
+        err,
+        format!("failed to read directory `{}`", path.display()),
+    )
+}
+
+fn permissions_error(err: io::Error, path: &Path) -> Error {
+    Error::io_context(
+        err,
+        format!("failed to set permissions of `{}`", path.display()),
+    )
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    // A relative target is interpreted from the directory containing the link.
+    let target = match link.parent() {
+        Some(parent) => parent.join(original),
+        None => original.to_path_buf(),
+    };
+
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(original, link)
+    } else {
+        std::os::windows::fs::symlink_file(original, link)
+    }
+}
+
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref err) if err.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return result,
+    }
+
+    if fs::symlink_metadata(from)?.is_dir() {
+        copy_tree(from, to, &mut |_| {})?;
+        fs::remove_dir_all(from)
+    } else {
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    }
+}
+
+/// Copies a directory tree, recreating symlinks rather than following them.
+/// Returns the number of non-directory entries copied.
+fn copy_tree(from: &Path, to: &Path, progress: &mut dyn FnMut(&Path)) -> io::Result<u64> {
+    fs::create_dir_all(to)?;
+
+    let mut count = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            symlink(&fs::read_link(entry.path())?, &dest)?;
+        } else if file_type.is_dir() {
+            count += copy_tree(&entry.path(), &dest, progress)?;
+            continue;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+        progress(&entry.path());
+        count += 1;
+    }
+
+    // Applied last so that a read-only source directory can still be populated.
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+
+    Ok(count)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+bitflags! {
+    pub struct CreateFlags: u32 {
+        const RECURSIVE = 0b_0000_0001;
+        const EXIST_OK = 0b_0000_0010;
+    }
+}
+
+bitflags! {
+    pub struct CopyFlags: u32 {
+        const RECURSIVE = 0b_0000_0001;
+    }
+}
+
+bitflags! {
+    pub struct WalkFlags: u32 {
+        const FOLLOW_LINKS = 0b_0000_0001;
+    }
+}
+
+bitflags! {
+    pub struct RemoveFlags: u32 {
+        const RECURSIVE = 0b_0000_0001;
+        const MISSING_OK = 0b_0000_0010;
+        /// Moves the path to `.xtask-trash` in the target directory instead of deleting
+        /// it, see `Shell::remove`.
+        const BACKUP = 0b_0000_0100;
+    }
+}
+
+/// Whether to colorize the output of the shell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colorize only if stderr is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The line ending used by `Shell::write_text`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`, as used on Unix.
+    #[default]
+    Lf,
+    /// `\r\n`, as used on Windows.
+    CrLf,
+}
+
+/// The quoting rules used to echo command lines and to split argument strings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quoting {
+    /// The rules of the host platform.
+    #[default]
+    Auto,
+    /// The rules of a POSIX shell.
+    Unix,
+    /// The rules of the Microsoft C runtime, used by most Windows programs.
+    Windows,
+}
+
+impl Quoting {
+    fn is_windows(self) -> bool {
+        match self {
+            Quoting::Auto => cfg!(windows),
+            Quoting::Unix => false,
+            Quoting::Windows => true,
+        }
+    }
+}
+
+/// A depth-first traversal created by `Shell::walk`.
+struct Walk {
+    stack: Vec<(PathBuf, fs::ReadDir)>,
+    follow_links: bool,
+    visited: HashSet<PathBuf>,
+    // The error from descending into the directory yielded last, reported right after it.
+    pending: Option<Error>,
+}
+
+impl Walk {
+    fn descend(&mut self, dir: &Path) -> Result<()> {
+        if self.follow_links {
+            let canonical = fs::canonicalize(dir).map_err(|err| read_dir_error(err, dir))?;
+            if !self.visited.insert(canonical) {
+                return Err(Error::msg(format!(
+                    "`{}` was already visited through a symlink",
+                    dir.display()
+                )));
+            }
+        }
+
+        let entries = fs::read_dir(dir).map_err(|err| read_dir_error(err, dir))?;
+        self.stack.push((dir.to_path_buf(), entries));
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.pending.take() {
+            return Some(Err(err));
+        }
+
+        loop {
+            let (dir, entries) = self.stack.last_mut()?;
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(read_dir_error(err, dir))),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            let path = entry.path();
+            let is_dir = match entry.file_type() {
+                Ok(file_type) if file_type.is_symlink() && self.follow_links => path.is_dir(),
+                Ok(file_type) => file_type.is_dir(),
+                Err(err) => return Some(Err(read_dir_error(err, &path))),
+            };
+
+            if is_dir {
+                self.pending = self.descend(&path).err();
+            }
+            return Some(Ok(path));
+        }
+    }
+}
+
+/// A builder to construct a `Shell` with explicitly given roots.
+#[derive(Debug, Default)]
+pub struct ShellBuilder {
+    project_root: Option<PathBuf>,
+    target_dir: Option<PathBuf>,
+    env_store: Option<EnvStore>,
+}
+
+impl ShellBuilder {
+    /// Sets the project root. Defaults to the workspace containing `CARGO_MANIFEST_DIR`.
+    pub fn project_root(mut self, path: impl Into<PathBuf>) -> Self {
+        self.project_root = Some(path.into());
+        self
+    }
+
+    /// Sets the target directory. Defaults to `CARGO_TARGET_DIR`, `CARGO_BUILD_TARGET_DIR`
+    /// or `target/` under the project root, in this order.
+    pub fn target_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target_dir = Some(path.into());
+        self
+    }
+
+    /// Sets the environment. Defaults to a fake copy of the process environment.
+    pub fn env_store(mut self, env_store: EnvStore) -> Self {
+        self.env_store = Some(env_store);
+        self
+    }
+
+    /// Builds the shell.
+    ///
+    /// Without an explicit project root, it is the workspace containing the directory in
+    /// `CARGO_MANIFEST_DIR`. If the variable is not set at run time, its value when this
+    /// crate was built is used instead, which cargo always provides; this keeps xtask
+    /// binaries working when they are run outside of `cargo run`. As a consequence, the
+    /// `missing CARGO_MANIFEST_DIR` error only occurs when the crate was not built by
+    /// cargo, or when an environment is given with `env_store`; such an environment is
+    /// used as it is, without the fallback.
+    pub fn build(self) -> Result<Shell> {
+        let built_with = match self.env_store {
+            Some(_) => None,
+            None => option_env!("CARGO_MANIFEST_DIR"),
+        };
+        let envs = self.env_store.unwrap_or_else(EnvStore::fake);
+
+        let project_root = match self.project_root {
+            Some(project_root) => project_root,
+            None => {
+                let manifest_dir = envs
+                    .var_os("CARGO_MANIFEST_DIR")
+                    .map(PathBuf::from)
+                    .or_else(|| built_with.map(PathBuf::from))
+                    .ok_or_else(|| {
+                        Error::msg("cannot determine the project root: missing CARGO_MANIFEST_DIR")
+                    })?;
+                // A manifest directory without a parent, such as `/`, is its own root.
+                find_workspace_root(&manifest_dir)
+                    .or_else(|| manifest_dir.parent())
+                    .filter(|root| !root.as_os_str().is_empty())
+                    .unwrap_or(&manifest_dir)
+                    .to_path_buf()
+            }
+        };
+
+        let target_dir = self.target_dir.unwrap_or_else(|| {
+            envs.var_os("CARGO_TARGET_DIR")
+                .or_else(|| envs.var_os("CARGO_BUILD_TARGET_DIR"))
+                .map(PathBuf::from)
+                .unwrap_or_else(|| project_root.join("target"))
+        });
+
+        let dry_run = envs.var_os("DRY_RUN").is_some();
+        let color = match envs.var_os("CARGO_TERM_COLOR") {
+            Some(ref color) if color == "always" => ColorChoice::Always,
+            Some(ref color) if color == "never" => ColorChoice::Never,
+            _ => ColorChoice::Auto,
+        };
+
+        Ok(Shell {
+            env_store: envs,
+            cwd: project_root.clone(),
+            project_root,
+            target_dir,
+            dry_run,
+            verbose: false,
+            color,
+            quoting: Quoting::Auto,
+            dry_run_log: DryRunLog {
+                commands: Arc::default(),
+                echo: true,
+            },
+            observer: None,
+        })
+    }
+}
+
+/// Receives the commands run through a shell, see `Shell::set_observer`.
+///
+/// The hooks are called from the thread running the command.
+pub trait ShellObserver: Send + Sync {
+    /// Called right before the command starts.
+    fn on_command(&self, line: &str);
+
+    /// Called once the command has finished, with its exit code. The code is `None` if
+    /// the command could not be started, timed out, was terminated by a signal, or was
+    /// spawned and its `Child` dropped without waiting for it.
+    fn on_finish(&self, line: &str, status: Option<i32>);
+}
+
+/// The commands skipped in dry-run mode, shared by a shell and its subprocesses.
+#[derive(Clone, Debug)]
+struct DryRunLog {
+    commands: Arc<Mutex<Vec<String>>>,
+    echo: bool,
+}
+
+impl DryRunLog {
+    fn lock(&self) -> MutexGuard<'_, Vec<String>> {
+        // The log stays consistent even if a thread panicked while holding the lock.
+        self.commands.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A temporary directory created by `Shell::temp_dir`, removed recursively on drop.
+pub struct TempDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// A temporary file created by `Shell::temp_file`, removed on drop.
+pub struct TempFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Environment variables set by `Shell::scoped_env`, restored on drop.
+pub struct EnvScope {
+    env_store: EnvStore,
+    saved: Vec<(OsString, Option<OsString>)>,
+}
+
+impl Drop for EnvScope {
+    fn drop(&mut self) {
+        // Restore in reverse so that a variable given twice gets its original value back.
+        for (key, old) in self.saved.drain(..).rev() {
+            match old {
+                Some(val) => self.env_store.set_var(key, val),
+                None => self.env_store.remove_var(key),
+            }
+        }
+    }
+}
+
+/// A thin wrapper to improve the convenience of `std::process::Command`.
+pub struct Subprocess {
+    command: Command,
+    dry_run: bool,
+    verbose: bool,
+    color: bool,
+    quoting: Quoting,
+    dry_run_log: DryRunLog,
+    observer: Option<Arc<dyn ShellObserver>>,
+    project_root: PathBuf,
+    cwd: PathBuf,
+    base_env: Vec<(OsString, OsString)>,
+    inherit_env: bool,
+    silent: bool,
+    buffered: bool,
+    stdin: Option<Input>,
+    timeout: Option<Duration>,
+    retry: Option<(u32, Duration)>,
+    kill_on_drop: bool,
+}
+
+impl Subprocess {
+    pub fn arg<S>(mut self, arg: S) -> Self
+    where
+        S: AsRef<OsStr>,
+    {
+        self.command.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    // ```
+    // $ {{ command }} $(if {{ cond }}; then echo {{ arg }}; fi)
+    // ```
+    pub fn arg_if<S>(self, cond: bool, arg: S) -> Self
+    where
+        S: AsRef<OsStr>,
+    {
+        if cond {
+            self.arg(arg)
+        } else {
+            self
+        }
+    }
+
+    pub fn args_if<I, S>(self, cond: bool, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        if cond {
+            self.args(args)
+        } else {
+            self
+        }
+    }
+
+    // Splits the string into arguments and appends them.
+    //
+    // With `Quoting::Unix`, this is done like a POSIX shell, honoring single and double
+    // quotes as well as backslash escapes. With `Quoting::Windows`, the rules of the
+    // Microsoft C runtime apply: only double quotes group, and backslashes are literal
+    // unless they precede a double quote.
+    //
+    // No expansion of any kind is performed. An unbalanced quote is an error.
+    pub fn args_from_str(self, s: &str) -> Result<Self> {
+        let args = split_args(s, self.quoting)?;
+        Ok(self.args(args))
+    }
+
+    pub fn env<K, V>(mut self, key: K, val: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.command.env(key, val);
+        self
+    }
+
+    // Sets several variables at once, on top of the shell's environment. If a key is
+    // given more than once, the last value wins.
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.command.envs(vars);
+        self
+    }
+
+    // ```
+    // $ (cd {{ dir }} && {{ command }})
+    // ```
+    //
+    // Overrides the working directory, which defaults to `Shell::current_dir`. A relative
+    // path is resolved against that default, and the last call wins.
+    pub fn current_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.command.current_dir(self.cwd.join(dir));
+        self
+    }
+
+    // ```
+    // $ {{ command }} --release
+    // ```
+    pub fn cargo_release(self) -> Self {
+        self.arg("--release")
+    }
+
+    // Unsets a variable, including one inherited from the shell's environment.
+    pub fn env_remove<K>(mut self, key: K) -> Self
+    where
+        K: AsRef<OsStr>,
+    {
+        self.command.env_remove(key);
+        self
+    }
+
+    // Starts from an empty environment, discarding both the shell's environment and the
+    // variables added so far. Variables added afterwards are still applied.
+    pub fn env_clear(mut self) -> Self {
+        self.command.env_clear();
+        self.base_env.clear();
+        self.inherit_env = false;
+        self
+    }
+
+    // Discards the variables set or removed so far, and starts over from the current
+    // environment of the shell, exactly as a newly created subprocess would.
+    pub fn reset_env(mut self, shell: &Shell) -> Self {
+        self.command.env_clear();
+        self.base_env = shell.captured_env();
+        self.inherit_env = false;
+        self
+    }
+
+    /// Chooses whether the subprocess starts from the real environment of the current
+    /// process rather than from the shell's environment store, which is the default.
+    ///
+    /// The store makes runs reproducible: only variables that were captured or set
+    /// explicitly reach the subprocess, so a test cannot depend on whatever happened to
+    /// be exported in the caller's terminal. Inheriting the real environment also passes
+    /// on variables the store never saw, including credentials and tokens, to the
+    /// subprocess. Variables set with `env` and `env_remove` are applied either way.
+    pub fn inherit_env(mut self, inherit: bool) -> Self {
+        self.inherit_env = inherit;
+        self
+    }
+
+    // ```
+    // $ {{ command }} <<< {{ data }}
+    // ```
+    pub fn stdin_bytes(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.command.stdin(Stdio::piped());
+        self.stdin = Some(Input::Bytes(data.into()));
+        self
+    }
+
+    /// Lets the subprocess read from the stdin of the current process, which is needed by
+    /// interactive commands such as `cargo login`. Only one source of input can be used:
+    /// this, `stdin_bytes` and `stdin_file` override each other, and the last call wins.
+    pub fn stdin_inherit(mut self) -> Self {
+        self.command.stdin(Stdio::inherit());
+        self.stdin = None;
+        self
+    }
+
+    // ```
+    // $ {{ command }} < {{ path }}
+    // ```
+    //
+    // The file is opened right away, so a missing file is reported here rather than when
+    // the command is run. A relative path is resolved against `Shell::current_dir`,
+    // like the one given to `current_dir`.
+    pub fn stdin_file(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = self.cwd.join(path);
+        let file = fs::File::open(&path).map_err(|err| {
+            Error::io_context(err, format!("failed to open `{}`", path.display()))
+        })?;
+        self.stdin = Some(Input::File(file));
+        Ok(self)
+    }
+
+    // ```
+    // $ timeout {{ duration }} {{ command }}
+    // ```
+    //
+    // Kills the subprocess if it has not finished within the given duration.
+    // A zero duration means no timeout.
+    //
+    // The subprocess is started in its own process group, so that its own children (e.g.
+    // the `rustc` processes of `cargo`) are killed along with it.
+    pub fn timeout(mut self, duration: Duration) -> Self {
+        self.timeout = if duration == Duration::ZERO {
+            None
+        } else {
+            Some(duration)
+        };
+        self
+    }
+
+    // ```
+    // $ until {{ command }}; do sleep {{ delay }}; done
+    // ```
+    //
+    // Makes `run` try the command up to `attempts` times in total, waiting `delay`
+    // between tries. The error of the last attempt is returned if all of them fail.
+    pub fn retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.retry = Some((attempts.max(1), delay));
+        self
+    }
+
+    // Makes dropping the `Child` returned by `spawn` kill the subprocess if it is still
+    // running. As with `timeout`, the whole process group is killed.
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    pub fn silent(mut self) -> Self {
+        self.command.stdout(Stdio::null());
+        self.command.stderr(Stdio::null());
+        self.silent = true;
+        self
+    }
+
+    // Captures stdout and stderr together, and only prints them (to stderr) if the
+    // command fails. `silent` takes precedence and suppresses the output even then.
+    //
+    // This applies to `run`, `status` and `run_allow_failure`.
+    pub fn buffered(mut self) -> Self {
+        self.buffered = true;
+        self
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        if self.skip() {
+            return Ok(());
+        }
+
+        self.run_attempts()
+    }
+
+    // ```
+    // $ time {{ command }}
+    // ```
+    //
+    // Like `run`, but returns how long the command took, including any retries.
+    pub fn run_timed(mut self) -> Result<Duration> {
+        if self.skip() {
+            return Ok(Duration::ZERO);
+        }
+
+        let start = Instant::now();
+        self.run_attempts()?;
+        Ok(start.elapsed())
+    }
+
+    /// Runs the command, retrying it if requested, and checks its status.
+    fn run_attempts(&mut self) -> Result<()> {
+        let (attempts, delay) = self.retry.unwrap_or((1, Duration::ZERO));
+        let mut attempt = 1;
+        let result = loop {
+            let result = self
+                .wait_status()
+                .and_then(|status| check_status(self.program(), status));
+            match result {
+                Err(err) if attempt < attempts => {
+                    eprintln!(
+                        "[cargo-xtask] {} (attempt {}/{}), retrying in {:?}",
+                        err, attempt, attempts, delay
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+
+        let code = match result {
+            Ok(()) => Some(0),
+            Err(ref err) => err.exit_code(),
+        };
+        self.notify_finish(code);
+        result
+    }
+
+    fn notify_command(&self) {
+        if let Some(ref observer) = self.observer {
+            observer.on_command(&self.command_line());
+        }
+    }
+
+    fn notify_finish(&self, code: Option<i32>) {
+        if let Some(ref observer) = self.observer {
+            observer.on_finish(&self.command_line(), code);
+        }
+    }
+
+    // ```
+    // $ {{ command }}; status=$?
+    // ```
+    //
+    // Unlike `run`, a non-zero exit code is *not* treated as an error.
+    pub fn status(mut self) -> Result<ExitStatus> {
+        if self.skip() {
+            return Ok(success_status());
+        }
+
+        let status = self.wait_status();
+        self.notify_finish(status.as_ref().ok().and_then(ExitStatus::code));
+        status
+    }
+
+    // ```
+    // $ if {{ command }}; then ...; fi
+    // ```
+    //
+    // Returns `Ok(false)` if the command ran but exited with a non-zero code, so that
+    // only failures to launch it are reported as errors.
+    pub fn run_allow_failure(self) -> Result<bool> {
+        Ok(self.status()?.success())
+    }
+
+    // ```
+    // $ if {{ command }} >/dev/null 2>&1; then ...; fi
+    // ```
+    //
+    // Like `run_allow_failure`, but with the output suppressed and a failure to launch
+    // the command also reported as `false`, e.g. to probe whether a tool is installed.
+    // Always `true` in dry-run mode.
+    pub fn succeeds(self) -> bool {
+        self.silent().run_allow_failure().unwrap_or(false)
+    }
+
+    // ```
+    // $ output=$({{ command }})
+    // ```
+    //
+    // Runs the command and returns its standard output as a string,
+    // with the trailing newlines removed.
+    //
+    // Output that is not valid UTF-8 is an error; use `read_lossy` to accept it.
+    pub fn read(mut self) -> Result<String> {
+        if self.skip() {
+            return Ok(String::new());
+        }
+
+        let stdout = self.read_stdout()?;
+        Ok(trim_newlines(decode(stdout, "stdout")?))
+    }
+
+    // ```
+    // $ output=$({{ command }})
+    // ```
+    //
+    // Like `read`, but replaces invalid UTF-8 with U+FFFD instead of failing, for tools
+    // that may print arbitrary bytes.
+    pub fn read_lossy(mut self) -> Result<String> {
+        if self.skip() {
+            return Ok(String::new());
+        }
+
+        let stdout = self.read_stdout()?;
+        Ok(trim_newlines(String::from_utf8_lossy(&stdout).into_owned()))
+    }
+
+    fn read_stdout(&mut self) -> Result<Vec<u8>> {
+        self.command.stdout(Stdio::piped());
+        let output = self.execute()?;
+        check_status(self.program(), output.status)?;
+        Ok(output.stdout)
+    }
+
+    // ```
+    // $ {{ command }} | jq -c .
+    // ```
+    //
+    // Runs the command and parses each non-empty line of its standard output as JSON,
+    // e.g. the output of `cargo build --message-format=json`. Lines that cannot be
+    // parsed are an error unless `skip_invalid` is set, in which case they are dropped.
+    #[cfg(feature = "serde")]
+    pub fn read_json_lines<T>(self, skip_invalid: bool) -> Result<Vec<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let program = self.program();
+        let stdout = self.read()?;
+
+        let mut values = Vec::new();
+        for (i, line) in stdout.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(value) => values.push(value),
+                Err(_) if skip_invalid => {}
+                Err(err) => {
+                    return Err(Error::msg(format!(
+                        "failed to parse line {} of the output of {} as JSON: {}",
+                        i + 1,
+                        program,
+                        err
+                    )))
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    // ```
+    // $ {{ command }} > >(tee {{ out }}) 2> >(tee {{ err }} >&2)
+    // ```
+    //
+    // Streams stdout and stderr into the given writers while the command runs, rather
+    // than buffering the output in memory.
+    pub fn run_to_writer(mut self, mut out: impl Write, mut err: impl Write) -> Result<()> {
+        if self.skip() {
+            return Ok(());
+        }
+
+        self.command.stdout(Stdio::piped());
+        self.command.stderr(Stdio::piped());
+        let status = self.start().and_then(|running| {
+            running.stream(|stream, chunk| match stream {
+                Stream::Stdout => out.write_all(chunk),
+                Stream::Stderr => err.write_all(chunk),
+            })
+        });
+        self.notify_finish(status.as_ref().ok().and_then(ExitStatus::code));
+        let status = status?;
+        out.flush().map_err(Error::io_error)?;
+        err.flush().map_err(Error::io_error)?;
+
+        check_status(self.program(), status)
+    }
+
+    // ```
+    // $ {{ command }} | while read line; do ...; done
+    // ```
+    //
+    // Calls `f` with each line of stdout as soon as it is printed, without the line
+    // terminator. Invalid UTF-8 is replaced with U+FFFD rather than reported as an error.
+    pub fn run_with_lines(mut self, mut f: impl FnMut(&str)) -> Result<()> {
+        if self.skip() {
+            return Ok(());
+        }
+
+        let mut line = Vec::new();
+        let mut emit = |line: &mut Vec<u8>| {
+            while let Some(b'\n' | b'\r') = line.last() {
+                line.pop();
+            }
+            f(&String::from_utf8_lossy(line));
+            line.clear();
+        };
+
+        self.command.stdout(Stdio::piped());
+        let status = self.start().and_then(|running| {
+            running.stream(|_, chunk| {
+                for piece in chunk.split_inclusive(|&b| b == b'\n') {
+                    line.extend_from_slice(piece);
+                    if piece.ends_with(b"\n") {
+                        emit(&mut line);
+                    }
+                }
+                Ok(())
+            })
+        });
+        self.notify_finish(status.as_ref().ok().and_then(ExitStatus::code));
+        let status = status?;
+        if !line.is_empty() {
+            emit(&mut line);
+        }
+
+        check_status(self.program(), status)
+    }
+
+    // ```
+    // $ errors=$({{ command }} 2>&1 >/dev/tty)
+    // ```
+    //
+    // Runs the command with only stderr captured and returns it, leaving stdout as is.
+    // As with `read`, output that is not valid UTF-8 is an error.
+    pub fn read_stderr(mut self) -> Result<String> {
+        if self.skip() {
+            return Ok(String::new());
+        }
+
+        self.command.stderr(Stdio::piped());
+        let output = self.execute()?;
+        check_status(self.program(), output.status)?;
+
+        decode(output.stderr, "stderr")
+    }
+
+    // ```
+    // $ {{ command }} > stdout 2> stderr; status=$?
+    // ```
+    /// Runs the command with both stdout and stderr captured and returns the raw output.
+    /// Unlike `run`, a non-zero exit code is *not* treated as an error; inspect
+    /// `Output::status` to decide what to do with it.
+    pub fn output(mut self) -> Result<Output> {
+        if self.skip() {
+            return Ok(Output {
+                status: success_status(),
+                stdout: vec![],
+                stderr: vec![],
+            });
+        }
+
+        self.command.stdout(Stdio::piped());
+        self.command.stderr(Stdio::piped());
+        self.execute()
+    }
+
+    // ```
+    // $ {{ command }} > stdout 2> stderr; status=$?
+    // ```
+    //
+    // Like `output`, but decodes stdout and stderr as text. A non-zero exit code is *not*
+    // treated as an error, while output that is not valid UTF-8 is.
+    pub fn run_capture(self) -> Result<CommandOutput> {
+        let output = self.output()?;
+        Ok(CommandOutput {
+            status: output.status,
+            stdout: decode(output.stdout, "stdout")?,
+            stderr: decode(output.stderr, "stderr")?,
+        })
+    }
+
+    // ```
+    // $ {{ command }} &
+    // ```
+    //
+    // Starts the command without waiting for it to finish. In dry-run mode, the returned
+    // handle does not refer to any process and reports success on `wait`.
+    pub fn spawn(mut self) -> Result<Child> {
+        if self.skip() {
+            return Ok(Child {
+                running: None,
+                kill_on_drop: false,
+                observer: None,
+            });
+        }
+
+        let running = match self.start() {
+            Ok(running) => running,
+            Err(err) => {
+                self.notify_finish(None);
+                return Err(err);
+            }
+        };
+        Ok(Child {
+            running: Some(running),
+            kill_on_drop: self.kill_on_drop,
+            observer: self
+                .observer
+                .clone()
+                .map(|observer| (observer, self.command_line())),
+        })
+    }
+
+    /// Echoes the command in verbose mode and reports whether it must be skipped.
+    fn skip(&self) -> bool {
+        // An observer replaces the echo, and is only notified if the command actually runs.
+        let skip = announce(
+            self.verbose && self.observer.is_none(),
+            self.color,
+            self.dry_run,
+            &self.dry_run_log,
+            || self.command_line(),
+        );
+        if !skip {
+            self.notify_command();
+        }
+        skip
+    }
+
+    // Renders the command as it would be typed into a shell started at the project root,
+    // without running anything. This is what is echoed in verbose mode.
+    //
+    // A working directory set with `current_dir` is included as a `cd` in a subshell.
+    pub fn command_line(&self) -> String {
+        let mut line = quote(self.command.get_program(), self.quoting).into_owned();
+        for arg in self.command.get_args() {
+            line.push(' ');
+            line.push_str(&quote(arg, self.quoting));
+        }
+
+        let dir = self.command.get_current_dir().unwrap_or(&self.project_root);
+        let dir = dir.strip_prefix(&self.project_root).unwrap_or(dir);
+        if dir.as_os_str().is_empty() {
+            return line;
+        }
+        format!("(cd {} && {})", quote(dir.as_os_str(), self.quoting), line)
+    }
+
+    fn wait_status(&mut self) -> Result<ExitStatus> {
+        if !self.buffered || self.silent {
+            return self.start()?.wait();
+        }
+
+        let (mut reader, writer) = io::pipe().map_err(Error::io_error)?;
+        self.command
+            .stdout(writer.try_clone().map_err(Error::io_error)?);
+        self.command.stderr(writer);
+        let running = self.start();
+        // Drop our copies of the write end so that the reader sees EOF once the child exits.
+        self.command.stdout(Stdio::null());
+        self.command.stderr(Stdio::null());
+
+        let running = running?;
+        let reader = thread::spawn(move || {
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        // The output is needed most when waiting failed, e.g. on a timeout, so it is
+        // collected before the result is looked at.
+        let status = running.wait();
+        let output = reader
+            .join()
+            .expect("the output reader thread panicked")
+            .map_err(Error::io_error)?;
+        if !status.as_ref().is_ok_and(ExitStatus::success) {
+            let _ = io::stderr().write_all(&output);
+        }
+
+        status
+    }
+
+    fn execute(&mut self) -> Result<Output> {
+        let output = self.start().and_then(Running::wait_with_output);
+        self.notify_finish(output.as_ref().ok().and_then(|output| output.status.code()));
+        output
+    }
+
+    fn start(&mut self) -> Result<Running> {
+        self.apply_env();
+        let data = match self.stdin {
+            Some(Input::Bytes(ref data)) => Some(data.clone()),
+            Some(Input::File(ref file)) => {
+                let mut file = file.try_clone().map_err(Error::io_error)?;
+                // Rewind in case an earlier attempt consumed the file. This fails for
+                // unseekable files such as FIFOs, which are passed on as they are.
+                let _ = file.seek(io::SeekFrom::Start(0));
+                self.command.stdin(file);
+                None
+            }
+            None => None,
+        };
+        // Only a subprocess that may be killed gets its own process group, since one in
+        // the background would no longer receive the Ctrl-C of the terminal.
+        let process_group = self.timeout.is_some() || self.kill_on_drop;
+        if process_group {
+            set_process_group(&mut self.command);
+        }
+        let mut child = self
+            .command
+            .spawn()
+            .map_err(|err| spawn_error(err, self.command.get_program()))?;
+
+        // The input is fed from a separate thread so that a child filling up its
+        // stdout/stderr pipes before draining stdin cannot deadlock us.
+        let writer = data.map(|data| {
+            let mut pipe = child.stdin.take().expect("stdin should be piped");
+            thread::spawn(move || pipe.write_all(&data))
+        });
+
+        Ok(Running {
+            child,
+            process_group,
+            writer,
+            program: self.program(),
+            timeout: self
+                .timeout
+                .map(|timeout| (Instant::now() + timeout, timeout)),
+        })
+    }
+
+    // Replaces the inherited environment with the captured one, keeping the variables
+    // set or removed on the command itself.
+    fn apply_env(&mut self) {
+        if self.inherit_env {
+            return;
+        }
+
+        let overrides: Vec<(OsString, Option<OsString>)> = self
+            .command
+            .get_envs()
+            .map(|(key, val)| (key.to_owned(), val.map(OsStr::to_owned)))
+            .collect();
+        self.command.env_clear();
+        self.command.envs(self.base_env.iter().map(|(k, v)| (k, v)));
+        for (key, val) in overrides {
+            match val {
+                Some(val) => self.command.env(key, val),
+                None => self.command.env_remove(key),
+            };
+        }
+    }
+
+    fn program(&self) -> String {
+        self.command.get_program().to_string_lossy().into_owned()
+    }
+
+    // ```
+    // $ {{ self }} | {{ next }}
+    // ```
+    pub fn pipe(self, next: Subprocess) -> Pipeline {
+        Pipeline {
+            stages: vec![self, next],
+        }
+    }
+}
+
+/// The exit status and text output of a subprocess, as returned by
+/// `Subprocess::run_capture`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// Returns whether the subprocess exited successfully.
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+}
+
+/// A handle to a subprocess started by `Subprocess::spawn`.
+pub struct Child {
+    running: Option<Running>,
+    kill_on_drop: bool,
+    // The observer to notify when the subprocess finishes, with its command line.
+    observer: Option<(Arc<dyn ShellObserver>, String)>,
+}
+
+impl Child {
+    /// Returns the OS-assigned process identifier, or `None` in dry-run mode.
+    pub fn id(&self) -> Option<u32> {
+        self.running.as_ref().map(|running| running.child.id())
+    }
+
+    /// Forces the subprocess to exit.
+    pub fn kill(&mut self) -> Result<()> {
+        match self.running {
+            Some(ref mut running) => running.kill().map_err(Error::io_error),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the subprocess to exit and returns its status without checking it.
+    pub fn wait(mut self) -> Result<ExitStatus> {
+        let status = match self.running.take() {
+            Some(running) => running.wait(),
+            None => return Ok(success_status()),
+        };
+        self.notify_finish(status.as_ref().ok().and_then(ExitStatus::code));
+        status
+    }
+
+    fn notify_finish(&mut self, code: Option<i32>) {
+        if let Some((observer, line)) = self.observer.take() {
+            observer.on_finish(&line, code);
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Some(ref mut running) = self.running {
+            if self.kill_on_drop && matches!(running.child.try_wait(), Ok(None)) {
+                let _ = running.kill();
+                let _ = running.child.wait();
+            }
+        }
+        // A subprocess that was not waited for is reported as finished without a status,
+        // so that the observer does not keep waiting for it.
+        self.notify_finish(None);
+    }
+}
+
+/// A spawned subprocess along with the thread feeding its stdin, if any.
+struct Running {
+    child: process::Child,
+    process_group: bool,
+    writer: Option<thread::JoinHandle<io::Result<()>>>,
+    program: String,
+    timeout: Option<(Instant, Duration)>,
+}
+
+impl Running {
+    fn wait(mut self) -> Result<ExitStatus> {
+        let status = self.wait_child()?;
+        Running::join(self.writer.take(), &self.program)?;
+        Ok(status)
+    }
+
+    fn wait_with_output(self) -> Result<Output> {
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        let status = self.stream(|stream, chunk| {
+            match stream {
+                Stream::Stdout => stdout.extend_from_slice(chunk),
+                Stream::Stderr => stderr.extend_from_slice(chunk),
+            }
+            Ok(())
+        })?;
+
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Hands the output of the piped streams to `sink` as it arrives, then waits for the
+    /// subprocess to exit. `sink` is called on the current thread.
+    fn stream(
+        mut self,
+        mut sink: impl FnMut(Stream, &[u8]) -> io::Result<()>,
+    ) -> Result<ExitStatus> {
+        let (tx, rx) = mpsc::channel();
+        if let Some(pipe) = self.child.stdout.take() {
+            Running::forward(pipe, Stream::Stdout, tx.clone());
+        }
+        if let Some(pipe) = self.child.stderr.take() {
+            Running::forward(pipe, Stream::Stderr, tx.clone());
+        }
+        drop(tx);
+
+        loop {
+            let received = match self.timeout {
+                Some((deadline, _)) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+
+            let result = match received {
+                Ok((stream, Ok(chunk))) => sink(stream, &chunk),
+                Ok((_, Err(err))) => Err(err),
+                Err(mpsc::RecvTimeoutError::Timeout) => return Err(self.kill_timed_out()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(err) = result {
+                let _ = self.kill();
+                let _ = self.child.wait();
+                return Err(Error::io_error(err));
+            }
+        }
+
+        self.wait()
+    }
+
+    fn forward(
+        mut pipe: impl Read + Send + 'static,
+        stream: Stream,
+        tx: mpsc::Sender<(Stream, io::Result<Vec<u8>>)>,
+    ) {
+        thread::spawn(move || {
+            let mut buf = [0; 8192];
+            loop {
+                let chunk = match pipe.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => Ok(buf[..len].to_vec()),
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = chunk.is_err();
+                if tx.send((stream, chunk)).is_err() || failed {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn wait_child(&mut self) -> Result<ExitStatus> {
+        let deadline = match self.timeout {
+            Some((deadline, _)) => deadline,
+            None => return self.child.wait().map_err(Error::io_error),
+        };
+
+        loop {
+            if let Some(status) = self.child.try_wait().map_err(Error::io_error)? {
+                return Ok(status);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(self.kill_timed_out());
+            }
+
+            thread::sleep(cmp::min(deadline - now, Duration::from_millis(10)));
+        }
+    }
+
+    fn kill_timed_out(&mut self) -> Error {
+        let _ = self.kill();
+        let _ = self.child.wait();
+
+        let timeout = self.timeout.map(|(_, timeout)| timeout).unwrap_or_default();
+        Error::msg(format!("{} timed out after {:?}", self.program, timeout))
+    }
+
+    // Kills the subprocess, along with its process group if it has one.
+    fn kill(&mut self) -> io::Result<()> {
+        if self.process_group && kill_process_group(&self.child).is_ok() {
+            return Ok(());
+        }
+        self.child.kill()
+    }
+
+    fn join(writer: Option<thread::JoinHandle<io::Result<()>>>, program: &str) -> Result<()> {
+        if let Some(writer) = writer {
+            // A broken pipe means the child exited without consuming all of its input.
+            writer
+                .join()
+                .expect("the stdin writer thread panicked")
+                .map_err(|err| {
+                    Error::io_context(err, format!("failed to write the stdin of `{}`", program))
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// The input fed to a subprocess, if not inherited or null.
+enum Input {
+    Bytes(Vec<u8>),
+    File(fs::File),
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A sequence of subprocesses whose stdout is connected to the stdin of the next one.
+pub struct Pipeline {
+    stages: Vec<Subprocess>,
+}
+
+impl Pipeline {
+    // ```
+    // $ {{ self }} | {{ next }}
+    // ```
+    pub fn pipe(mut self, next: Subprocess) -> Self {
+        self.stages.push(next);
+        self
+    }
+
+    // Runs all stages concurrently and waits for them to finish.
+    //
+    // Like `set -o pipefail`, the pipeline fails if any of the stages exits with a non-zero
+    // code; the error reports the last stage that failed.
+    pub fn run(self) -> Result<()> {
+        // The stages are reported one by one to an observer instead.
+        let observed = self.stages.iter().any(|stage| stage.observer.is_some());
+        let verbose = !observed && self.stages.iter().any(|stage| stage.verbose);
+        let color = self.stages.iter().any(|stage| stage.color);
+        let dry_run = self.stages.iter().any(|stage| stage.dry_run);
+        let command_line = || {
+            let stages: Vec<_> = self.stages.iter().map(Subprocess::command_line).collect();
+            stages.join(" | ")
+        };
+        let log = &self.stages[0].dry_run_log;
+        if announce(verbose, color, dry_run, log, command_line) {
+            return Ok(());
+        }
+
+        let last = self.stages.len() - 1;
+        let mut running: Vec<(Subprocess, Running)> = Vec::with_capacity(self.stages.len());
+        let mut upstream: Option<process::ChildStdout> = None;
+
+        for (i, mut stage) in self.stages.into_iter().enumerate() {
+            if let Some(stdout) = upstream.take() {
+                stage.stdin = None;
+                stage.command.stdin(Stdio::from(stdout));
+            }
+            if i < last {
+                stage.command.stdout(Stdio::piped());
+            }
+
+            stage.notify_command();
+            let mut proc = match stage.start() {
+                Ok(proc) => proc,
+                Err(err) => {
+                    stage.notify_finish(None);
+                    for (stage, mut proc) in running {
+                        let _ = proc.kill();
+                        let _ = proc.wait();
+                        stage.notify_finish(None);
+                    }
+                    return Err(err);
+                }
+            };
+            upstream = proc.child.stdout.take();
+            running.push((stage, proc));
+        }
+
+        let mut result = Ok(());
+        for (i, (stage, proc)) in running.into_iter().enumerate() {
+            let status = proc.wait();
+            stage.notify_finish(status.as_ref().ok().and_then(ExitStatus::code));
+            // Every stage is waited for, so that each one is reported as finished.
+            match status {
+                Ok(status) if !status.success() => {
+                    let program = format!("pipeline stage {} ({})", i + 1, stage.program());
+                    result = check_status(program, status);
+                }
+                Ok(_) => {}
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+}
+
+/// A list of subprocesses run one after another, as long as they succeed.
+pub struct Sequence {
+    steps: Vec<Subprocess>,
+    dry_run: bool,
+    verbose: bool,
+    color: bool,
+    dry_run_log: DryRunLog,
+}
+
+impl Sequence {
+    // ```
+    // $ {{ self }} && {{ step }}
+    // ```
+    pub fn then(mut self, step: Subprocess) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    // Runs the steps in order and stops at the first one that fails, which the error
+    // identifies by its position, whether it exited with a non-zero code or could not be
+    // run at all. In dry-run mode, none of the steps are run.
+    pub fn run(self) -> Result<()> {
+        // The steps are reported one by one to an observer instead.
+        let observed = self.steps.iter().any(|step| step.observer.is_some());
+        let verbose = !observed && (self.verbose || self.steps.iter().any(|step| step.verbose));
+        let color = self.color || self.steps.iter().any(|step| step.color);
+        let dry_run = self.dry_run || self.steps.iter().any(|step| step.dry_run);
+        let command_line = || {
+            let steps: Vec<_> = self.steps.iter().map(Subprocess::command_line).collect();
+            steps.join(" && ")
+        };
+        if announce(verbose, color, dry_run, &self.dry_run_log, command_line) {
+            return Ok(());
+        }
+
+        for (i, mut step) in self.steps.into_iter().enumerate() {
+            step.notify_command();
+            let program = step.program();
+            step.run_attempts().map_err(|err| match err.0 {
+                ErrorKind::Exit { program, code } => {
+                    Error::exit(format!("sequence step {} ({})", i + 1, program), code)
+                }
+                ErrorKind::Io(err) => {
+                    Error::io_context(err, format!("sequence step {} ({})", i + 1, program))
+                }
+                ErrorKind::Context { context, source } => {
+                    Error::io_context(source, format!("sequence step {}: {}", i + 1, context))
+                }
+                ErrorKind::Msg(msg) => Error::msg(format!("sequence step {}: {}", i + 1, msg)),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+fn with_exe_extension(program: OsString) -> OsString {
+    program
+}
+
+/// Appends `.exe` to a path that only exists with it, since a `RUSTC` or `CARGO` set by
+/// hand on Windows often lacks the extension.
+#[cfg(windows)]
+fn with_exe_extension(program: OsString) -> OsString {
+    let path = Path::new(&program);
+    if path.components().count() > 1 && path.extension().is_none() && !path.is_file() {
+        let exe = path.with_extension("exe");
+        if exe.is_file() {
+            return exe.into_os_string();
+        }
+    }
+    program
+}
+
+fn spawn_error(err: io::Error, program: &OsStr) -> Error {
+    let program = Path::new(program);
+    let context = match err.kind() {
+        io::ErrorKind::NotFound if program.components().count() == 1 => {
+            format!("`{}` not found on PATH", program.display())
+        }
+        io::ErrorKind::NotFound => format!("`{}` does not exist", program.display()),
+        io::ErrorKind::PermissionDenied => format!("cannot execute `{}`", program.display()),
+        _ => format!("failed to execute `{}`", program.display()),
+    };
+    Error::io_context(err, context)
+}
+
+#[cfg(unix)]
+fn set_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(windows)]
+fn set_process_group(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(CREATE_NEW_PROCESS_GROUP);
+}
+
+#[cfg(unix)]
+fn kill_process_group(child: &process::Child) -> io::Result<()> {
+    // The group was created with the child as its leader, so its id is the child's pid.
+    let pgid = child.id() as libc::pid_t;
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn kill_process_group(child: &process::Child) -> io::Result<()> {
+    let status = Command::new("taskkill")
+        .args(["/T", "/F", "/PID"])
+        .arg(child.id().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other("taskkill failed"));
+    }
+    Ok(())
+}
+
+fn announce(
+    verbose: bool,
+    color: bool,
+    dry_run: bool,
+    log: &DryRunLog,
+    command_line: impl FnOnce() -> String,
+) -> bool {
+    if !verbose && !dry_run {
+        return false;
+    }
+
+    let command_line = command_line();
+    if verbose && color {
+        eprintln!(
+            "\x1b[1;32m[cargo-xtask]\x1b[0m \x1b[1m$ {}\x1b[0m",
+            command_line
+        );
+    } else if verbose {
+        eprintln!("[cargo-xtask] $ {}", command_line);
+    }
+    if dry_run {
+        if log.echo {
+            eprintln!("[cargo-xtask] - skipped");
+        }
+        log.lock().push(command_line);
+    }
+    dry_run
+}
+
+/// Quotes an argument so that the echoed command line can be pasted into a shell.
+fn quote(arg: &OsStr, quoting: Quoting) -> Cow<'_, str> {
+    let arg = arg.to_string_lossy();
+    if quoting.is_windows() {
+        return quote_windows(arg);
+    }
+
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return arg;
+    }
+    format!("'{}'", arg.replace('\'', "'\\''")).into()
+}
+
+fn quote_windows(arg: Cow<'_, str>) -> Cow<'_, str> {
+    if !arg.is_empty() && !arg.contains(&[' ', '\t', '\n', '"'][..]) {
+        return arg;
+    }
+
+    // Backslashes are only special before a double quote, including the closing one.
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.extend(iter::repeat_n('\\', backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            c => {
+                quoted.extend(iter::repeat_n('\\', backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    quoted.extend(iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted.into()
+}
+
+fn split_args(s: &str, quoting: Quoting) -> Result<Vec<String>> {
+    if quoting.is_windows() {
+        split_windows_args(s)
+    } else {
+        split_unix_args(s)
+    }
+}
+
+fn split_windows_args(s: &str) -> Result<Vec<String>> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut in_quotes = false;
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' if !in_quotes => args.extend(arg.take()),
+            '\\' => {
+                let mut backslashes = 1;
+                while chars.next_if_eq(&'\\').is_some() {
+                    backslashes += 1;
+                }
+                let arg = arg.get_or_insert_with(String::new);
+                if chars.peek() == Some(&'"') {
+                    // 2n backslashes escape themselves, and one more escapes the quote.
+                    arg.extend(iter::repeat_n('\\', backslashes / 2));
+                    if backslashes % 2 == 1 {
+                        chars.next();
+                        arg.push('"');
+                    }
+                } else {
+                    arg.extend(iter::repeat_n('\\', backslashes));
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                if in_quotes && chars.next_if_eq(&'"').is_some() {
+                    arg.push('"');
+                } else {
+                    in_quotes = !in_quotes;
+                }
+            }
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if in_quotes {
+        return Err(Error::msg(format!("unbalanced quote in `{}`", s)));
+    }
+    args.extend(arg);
+
+    Ok(args)
+}
+
+fn split_unix_args(s: &str) -> Result<Vec<String>> {
+    let unbalanced = || Error::msg(format!("unbalanced quote in `{}`", s));
+
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unbalanced)? {
+                        '\'' => break,
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or_else(unbalanced)? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or_else(unbalanced)? {
+                            '\n' => {}
+                            c @ ('\\' | '"' | '$' | '`') => arg.push(c),
+                            c => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                        },
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => arg.get_or_insert_with(String::new).push(c),
+                None => {
+                    return Err(Error::msg(format!("trailing backslash in `{}`", s)));
+                }
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+
+    Ok(args)
+}
+
+#[cfg(unix)]
+fn success_status() -> ExitStatus {
+    std::os::unix::process::ExitStatusExt::from_raw(0)
+}
+
+#[cfg(windows)]
+fn success_status() -> ExitStatus {
+    std::os::windows::process::ExitStatusExt::from_raw(0)
+}
+
+fn decode(bytes: Vec<u8>, stream: &str) -> Result<String> {
+    String::from_utf8(bytes)
+        .map_err(|_| Error::msg(format!("Subprocess wrote invalid UTF-8 to {}", stream)))
+}
+
+fn trim_newlines(mut s: String) -> String {
+    let len = s.trim_end_matches(&['\r', '\n'][..]).len();
+    s.truncate(len);
+    s
+}
+
+fn check_status(program: impl Into<String>, st: ExitStatus) -> Result<()> {
+    if !st.success() {
+        return Err(Error::exit(program, st.code()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Called by `Shell::remove` right before it inspects a path that could not be
+        /// removed as a file, to simulate a concurrent deletion.
+        static BEFORE_INSPECT: Cell<Option<fn(&Path)>> = const { Cell::new(None) };
+    }
+
+    pub(super) fn before_inspect(path: &Path) {
+        if let Some(hook) = BEFORE_INSPECT.with(Cell::take) {
+            hook(path);
+        }
+    }
+
+    /// Creates an empty directory that is unique to the calling test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("taski-shell-{}-{}", name, unique_name()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn empty_env_store() -> EnvStore {
+        let env_store = EnvStore::fake();
+        for (key, _) in env_store.vars_os() {
+            env_store.remove_var(key);
+        }
+        env_store
+    }
+
+    fn fixture_shell(root: &Path) -> Shell {
+        Shell::builder()
+            .project_root(root)
+            .env_store(EnvStore::fake())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn find_workspace_root_skips_members() {
+        let root = fixture_dir("workspace-root");
+        let member = root.join("crates").join("a");
+        fs::create_dir_all(&member).unwrap();
+        fs::write(member.join("Cargo.toml"), "[package]\nname = \"a\"\n").unwrap();
+        fs::write(
+            root.join("crates").join("Cargo.toml"),
+            "[package]\nname = \"b\"\n",
+        )
+        .unwrap();
+
+        for manifest in &[
+            "[workspace] # comment\nmembers = [\"crates/a\"]\n",
+            "workspace.members = [\"crates/a\"]\n",
+            "workspace = { members = [\"crates/a\"] }\n",
+        ] {
+            fs::write(root.join("Cargo.toml"), manifest).unwrap();
+            assert_eq!(find_workspace_root(&member), Some(root.as_path()));
+        }
+
+        fs::write(root.join("Cargo.toml"), "[package]\nname = \"root\"\n").unwrap();
+        assert_eq!(find_workspace_root(&member), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn remove_missing_path() {
+        let root = fixture_dir("remove-missing");
+        let sh = fixture_shell(&root);
+        let missing = root.join("missing");
+
+        let err = sh.remove(&missing, RemoveFlags::empty()).unwrap_err();
+        assert!(err.to_string().starts_with("failed to remove"));
+        sh.remove(&missing, RemoveFlags::MISSING_OK).unwrap();
+        sh.remove(&missing, RemoveFlags::RECURSIVE | RemoveFlags::MISSING_OK)
+            .unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn remove_tree_deleted_concurrently() {
+        let root = fixture_dir("remove-concurrent");
+        let sh = fixture_shell(&root);
+        let tree = root.join("tree");
+
+        for &flags in &[RemoveFlags::RECURSIVE, RemoveFlags::empty()] {
+            fs::create_dir_all(tree.join("a")).unwrap();
+            fs::write(tree.join("a").join("file"), "").unwrap();
+            // Another process removes the tree after it failed to be removed as a file.
+            BEFORE_INSPECT.with(|hook| hook.set(Some(|path| fs::remove_dir_all(path).unwrap())));
+            sh.remove(&tree, flags | RemoveFlags::MISSING_OK).unwrap();
+            assert!(!tree.exists());
+
+            fs::create_dir_all(tree.join("a")).unwrap();
+            BEFORE_INSPECT.with(|hook| hook.set(Some(|path| fs::remove_dir_all(path).unwrap())));
+            let err = sh.remove(&tree, flags).unwrap_err();
+            let source = error::Error::source(&err).unwrap();
+            let source = source.downcast_ref::<io::Error>().unwrap();
+            assert_eq!(source.kind(), io::ErrorKind::NotFound);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn write_atomic_replaces_file() {
+        let root = fixture_dir("write-atomic");
+        let sh = fixture_shell(&root);
+        let path = root.join("script.sh");
+        fs::write(&path, "old").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        }
+
+        sh.write_atomic(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o750);
+        }
+        let entries: Vec<_> = fs::read_dir(&root).unwrap().collect();
+        assert_eq!(entries.len(), 1, "the temporary file is left behind");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn subprocess_env_is_sorted() {
+        let env_store = empty_env_store();
+        for key in &["ZETA", "ALPHA", "MU", "BETA"] {
+            env_store.set_var(key, "1");
+        }
+        let sh = Shell::builder()
+            .project_root(env::temp_dir())
+            .env_store(env_store)
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            let keys: Vec<_> = sh
+                .subprocess("true")
+                .base_env
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(keys, ["ALPHA", "BETA", "MU", "ZETA"]);
+        }
+    }
+
+    #[test]
+    fn subprocess_current_dir_follows_cd() {
+        let root = fixture_dir("cd");
+        fs::create_dir_all(root.join("crates").join("a").join("sub")).unwrap();
+        let mut sh = fixture_shell(&root);
+
+        sh.cd("crates").unwrap();
+        sh.cd("a").unwrap();
+        let subprocess = sh.subprocess("true");
+        assert_eq!(
+            subprocess.command.get_current_dir(),
+            Some(root.join("crates").join("a").as_path())
+        );
+        let subprocess = subprocess.current_dir("sub");
+        assert_eq!(
+            subprocess.command.get_current_dir(),
+            Some(root.join("crates").join("a").join("sub").as_path())
+        );
+
+        fs::write(root.join("crates").join("a").join("input"), "").unwrap();
+        assert!(sh.is_file("input"));
+        assert!(sh.is_dir("sub"));
+        assert!(!sh.exists("crates"));
+        assert!(subprocess.stdin_file("input").is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn builder_with_shallow_manifest_dir() {
+        for (manifest_dir, project_root) in &[("/", "/"), ("xtask", "xtask")] {
+            let env_store = empty_env_store();
+            env_store.set_var("CARGO_MANIFEST_DIR", manifest_dir);
+            let sh = Shell::builder().env_store(env_store).build().unwrap();
+
+            assert_eq!(sh.project_root(), Path::new(project_root));
+            assert_eq!(sh.target_dir(), Path::new(project_root).join("target"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn observer_sees_every_command_finish() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl ShellObserver for Arc<Recorder> {
+            fn on_command(&self, line: &str) {
+                self.0.lock().unwrap().push(format!("start {}", line));
+            }
+
+            fn on_finish(&self, line: &str, status: Option<i32>) {
+                let event = format!("finish {} {:?}", line, status);
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut sh = fixture_shell(&env::temp_dir());
+        sh.set_observer(recorder.clone());
+
+        sh.subprocess("echo").arg("a").read().unwrap();
+        sh.subprocess("false").output().unwrap();
+        sh.subprocess("echo")
+            .arg("b")
+            .pipe(sh.subprocess("cat").silent())
+            .run()
+            .unwrap();
+        sh.subprocess("true").spawn().unwrap().wait().unwrap();
+        assert!(sh.subprocess("does-not-exist").read().is_err());
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(
+            *events,
+            [
+                "start echo a",
+                "finish echo a Some(0)",
+                "start false",
+                "finish false Some(1)",
+                "start echo b",
+                "start cat",
+                "finish echo b Some(0)",
+                "finish cat Some(0)",
+                "start true",
+                "finish true Some(0)",
+                "start does-not-exist",
+                "finish does-not-exist None",
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_backup_moves_tree_to_trash() {
+        let root = fixture_dir("remove-backup");
+        let target_dir = root.join("target");
+        let sh = Shell::builder()
+            .project_root(&root)
+            .target_dir(&target_dir)
+            .env_store(EnvStore::fake())
+            .build()
+            .unwrap();
+        let tree = root.join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("sub").join("file"), "content").unwrap();
+
+        assert!(sh.remove(&tree, RemoveFlags::BACKUP).is_err());
+        sh.remove(&tree, RemoveFlags::BACKUP | RemoveFlags::RECURSIVE)
+            .unwrap();
+        assert!(!tree.exists());
+        let trashed: Vec<_> = fs::read_dir(target_dir.join(".xtask-trash"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(trashed.len(), 1);
+        assert!(trashed[0].to_string_lossy().ends_with("-tree"));
+        let file = trashed[0].join("sub").join("file");
+        assert_eq!(fs::read_to_string(file).unwrap(), "content");
+
+        // The target directory contains the trash, however it is spelled.
+        fs::create_dir_all(root.join("other")).unwrap();
+        for path in &[
+            target_dir.clone(),
+            root.join("other").join("..").join("target"),
+        ] {
+            let flags = RemoveFlags::BACKUP | RemoveFlags::RECURSIVE;
+            let err = sh.remove(path, flags).unwrap_err();
+            assert!(err.to_string().contains("to the trash inside it"));
+        }
+        assert!(target_dir.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn builder_with_empty_env_store() {
+        let err = Shell::with_env_store(empty_env_store()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cannot determine the project root: missing CARGO_MANIFEST_DIR"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_yields_directories_that_cannot_be_descended() {
+        let root = fixture_dir("walk");
+        let sh = fixture_shell(&root);
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir").join("file"), "").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("dir").join("loop")).unwrap();
+
+        let entries: Vec<_> = sh
+            .walk(&root, WalkFlags::FOLLOW_LINKS)
+            .unwrap()
+            .map(|entry| entry.map_err(|err| err.to_string()))
+            .collect();
+        let mut paths: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| entry.clone().ok())
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                root.join("dir"),
+                root.join("dir").join("file"),
+                root.join("dir").join("loop")
+            ]
+        );
+        // The error comes right after the directory it is about.
+        let loop_index = entries
+            .iter()
+            .position(|entry| entry.as_ref().ok() == Some(&root.join("dir").join("loop")))
+            .unwrap();
+        let err = entries[loop_index + 1].as_ref().unwrap_err();
+        assert!(err.contains("was already visited through a symlink"));
+        assert_eq!(entries.len(), 4);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sequence_errors_name_the_step() {
+        let sh = fixture_shell(&env::temp_dir());
+        let run = |step: Subprocess| {
+            let err = sh.sequence().then(sh.subprocess("true")).then(step).run();
+            err.unwrap_err().to_string()
+        };
+
+        assert_eq!(
+            run(sh.subprocess("false")),
+            "sequence step 2 (false) exited with code 1"
+        );
+        assert!(run(sh.subprocess("does-not-exist"))
+            .starts_with("sequence step 2: `does-not-exist` not found on PATH"));
+        assert_eq!(
+            run(sh
+                .subprocess("sleep")
+                .arg("5")
+                .timeout(Duration::from_millis(100))),
+            "sequence step 2: sleep timed out after 100ms"
+        );
     }
 }